@@ -0,0 +1,189 @@
+//! The ALU: results and status flags of every arithmetic and logic operation.
+//!
+//! Operands and results are 8-bit. Flags come in complementary pairs, with
+//! the even-numbered flag set when the condition holds:
+//!
+//! | flags   | set by         | meaning                                          |
+//! |---------|----------------|--------------------------------------------------|
+//! | ZE / NZ | `add` .. `rsh` | the result is (not) zero                         |
+//! | CA / NC | `add`, `sub`   | unsigned carry out of bit 7; for `sub`, a borrow |
+//! | OF / NO | `add`, `sub`   | signed (two's complement) overflow               |
+//! | EV / OD | `add` .. `rsh` | the result is even (odd)                         |
+//! | GR / LE | `cmp`, `cms`   | `a > b` (`a <= b`)                               |
+//! | LS / GE | `cmp`, `cms`   | `a < b` (`a >= b`)                               |
+//! | EQ / NE | `cmp`, `cms`   | `a == b` (`a != b`)                              |
+//! | US      | `cmp`, `cms`   | `a < b` with both operands read as signed        |
+//! | TR      | `cmp`, `cms`   | always set, for unconditional branches           |
+//!
+//! `cmp` orders GR, LE, LS and GE unsigned and `cms` (on machines with signed
+//! compare) orders them signed (two's complement), so `cms` followed by
+//! `brc LS` branches on a signed less-than.
+//! US is the signed less-than under either form, which lets a program test
+//! both orderings after a single `cmp`.
+//!
+//! The logic operations (`and`, `nor`, `xor`, `rsh`) clear CA, NC, OF and NO.
+//! `cmp` and `cms` touch only flags 8-15 and the other operations only flags 0-7.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Add,
+    Sub,
+    And,
+    Nor,
+    Xor,
+    /// Logical shift right by one; the second operand is ignored.
+    Rsh,
+}
+
+/// Result of one ALU operation, before it is written back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Output {
+    pub result: u8,
+    pub carry: bool,
+    pub overflow: bool,
+}
+
+impl Output {
+    /// Flags 0-7 (ZE, NZ, CA, NC, OF, NO, EV, OD) for this output.
+    pub fn flags(&self, op: Op) -> [bool; 8] {
+        let zero = self.result == 0;
+        let even = self.result.is_multiple_of(2);
+        match op {
+            Op::Add | Op::Sub => [zero, !zero, self.carry, !self.carry, self.overflow, !self.overflow, even, !even],
+            Op::And | Op::Nor | Op::Xor | Op::Rsh => [zero, !zero, false, false, false, false, even, !even],
+        }
+    }
+}
+
+pub fn execute(op: Op, a: u8, b: u8) -> Output {
+    let (result, carry, overflow) = match op {
+        Op::Add => {
+            let (result, carry) = a.overflowing_add(b);
+            (result, carry, (a as i8).overflowing_add(b as i8).1)
+        }
+        Op::Sub => {
+            let (result, borrow) = a.overflowing_sub(b);
+            (result, borrow, (a as i8).overflowing_sub(b as i8).1)
+        }
+        Op::And => (a & b, false, false),
+        Op::Nor => (!(a | b), false, false),
+        Op::Xor => (a ^ b, false, false),
+        Op::Rsh => (a >> 1, false, false),
+    };
+    Output { result, carry, overflow }
+}
+
+/// Flags 8-15 (GR, LE, LS, GE, EQ, NE, US, TR) set by `cmp a, b`, or by
+/// `cms a, b` when `signed`.
+pub fn compare(a: u8, b: u8, signed: bool) -> [bool; 8] {
+    let less_signed = (a as i8) < (b as i8);
+    let (greater, less) = match signed {
+        true => ((a as i8) > (b as i8), less_signed),
+        false => (a > b, a < b),
+    };
+    [greater, !greater, less, !less, a == b, a != b, less_signed, true]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cpu::Cpu, isa::Isa, machine::Machine};
+
+    const OPS: [Op; 6] = [Op::Add, Op::Sub, Op::And, Op::Nor, Op::Xor, Op::Rsh];
+
+    fn operands() -> impl Iterator<Item = (u8, u8)> {
+        (0..=255).flat_map(|a| (0..=255).map(move |b| (a, b)))
+    }
+
+    /// Reference model on wide integers, independent of the wrapping primitives used above.
+    fn reference(op: Op, a: u8, b: u8) -> (u8, bool, bool) {
+        let (ua, ub) = (a as i32, b as i32);
+        let (sa, sb) = (a as i8 as i32, b as i8 as i32);
+        let (wide, signed) = match op {
+            Op::Add => (ua + ub, sa + sb),
+            Op::Sub => (ua - ub, sa - sb),
+            Op::And => (ua & ub, 0),
+            Op::Nor => (!(ua | ub) & 0xff, 0),
+            Op::Xor => (ua ^ ub, 0),
+            Op::Rsh => (ua / 2, 0),
+        };
+        let result = wide.rem_euclid(256) as u8;
+        match op {
+            Op::Add | Op::Sub => (result, !(0..=255).contains(&wide), !(-128..=127).contains(&signed)),
+            _ => (result, false, false),
+        }
+    }
+
+    #[test]
+    fn results_and_flags_match_reference() {
+        for op in OPS {
+            for (a, b) in operands() {
+                let (result, carry, overflow) = reference(op, a, b);
+                let output = execute(op, a, b);
+                assert_eq!(output, Output { result, carry, overflow }, "{:?} {} {}", op, a, b);
+
+                let arithmetic = matches!(op, Op::Add | Op::Sub);
+                let flags = output.flags(op);
+                let expected = [result == 0, result != 0,
+                                carry, arithmetic && !carry,
+                                overflow, arithmetic && !overflow,
+                                result % 2 == 0, result % 2 == 1];
+                assert_eq!(flags, expected, "{:?} {} {}", op, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn compare_matches_reference() {
+        for signed in [false, true] {
+            for (a, b) in operands() {
+                let (sa, sb) = (a as i8 as i32, b as i8 as i32);
+                let (a32, b32) = match signed {
+                    true => (sa, sb),
+                    false => (a as i32, b as i32),
+                };
+                let expected = [a32 > b32, a32 <= b32, a32 < b32, a32 >= b32, a32 == b32, a32 != b32, sa < sb, true];
+                assert_eq!(compare(a, b, signed), expected, "signed {} {} {}", signed, a, b);
+            }
+        }
+    }
+
+    /// Every ALU opcode, run through `Cpu::step`, writes the ALU result and
+    /// flags and leaves the other half of the flag register untouched. Bit 8
+    /// of `cmp` selects `cms` only on machines with signed compare.
+    #[test]
+    fn cpu_uses_alu_for_every_opcode() {
+        let opcodes = [(0x1312, Some(Op::Add)), (0x2312, Some(Op::Sub)), (0x3312, Some(Op::And)),
+                       (0x4312, Some(Op::Nor)), (0x5312, Some(Op::Xor)), (0x6312, Some(Op::Rsh)),
+                       (0x7012, None), (0x7112, None)];
+        for signed_compare in [false, true] {
+            let machine = Machine { signed_compare, isa: Isa::nano_with(false, signed_compare), ..Machine::default() };
+            let mut cpu = Cpu::with_machine(machine);
+            for (word, op) in opcodes {
+                // dest r3 (ignored by cmp/cms), sources r1 and r2.
+                cpu.write_to_rom(0, word);
+                let signed = signed_compare && word & 0x100 != 0;
+                for (a, b) in operands() {
+                    cpu.pc = 0;
+                    cpu.reg.copy_from_slice(&[0x5a, a, b, 0xa5, 0, 0, 0, 0]);
+                    let before = cpu.flg;
+                    cpu.step();
+
+                    match op {
+                        Some(op) => {
+                            let output = execute(op, a, b);
+                            assert_eq!(cpu.reg[3], output.result, "{:?} {} {}", op, a, b);
+                            assert_eq!(cpu.flg[..8], output.flags(op), "{:?} {} {}", op, a, b);
+                            assert_eq!(cpu.flg[8..], before[8..], "{:?} {} {}", op, a, b);
+                        }
+                        None => {
+                            assert_eq!(cpu.reg[3], 0xa5, "{:04x} {} {}", word, a, b);
+                            assert_eq!(cpu.flg[8..], compare(a, b, signed), "{:04x} {} {}", word, a, b);
+                            assert_eq!(cpu.flg[..8], before[..8], "{:04x} {} {}", word, a, b);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,329 @@
+//! Two-pass assembler for the mnemonics of a machine's instruction set (see
+//! `isa`; the stock AnPU Nano set is described here).
+//!
+//! Source is one instruction per line, using the same operand order that the
+//! emulator log prints (`add 1, 2, 3`, `brc NZ, loop`, ...). Registers may be
+//! written as `3` or `r3`, numbers as decimal, `0x` hex or `0b` binary, and
+//! branch conditions as flag names or flag indices. Labels end with `:` and may
+//! share a line with an instruction; `;` and `//` start a comment. `dml` and
+//! `dms` reach the I/O ports through `inp0`-`inp7` and `out0`-`out7`.
+//! On machines with signed compare, `cms` is `cmp` with signed ordering (bit 8
+//! set). `int` takes an optional vector (0-15) and argument (`int 2, 3` prints
+//! r3 under the default vector table); the raw 12-bit operand that older
+//! listings print is written in full as `int 0x005`.
+//! Registers, RAM addresses and jump targets are checked against the sizes of
+//! the machine the program is assembled for. A mnemonic with several forms
+//! assembles with the first form whose operands fit.
+
+use std::{collections::HashMap, fmt};
+
+use crate::{cpu::{FLAG_NAMES, IO_BASE}, isa::{Definition, Kind, Operand}, machine::Machine};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line + 1, self.message)
+    }
+}
+
+fn error(line: usize, message: String) -> AsmError {
+    AsmError { line, message }
+}
+
+/// Assembles `source` into ROM words for `machine`.
+pub fn assemble(source: &str, machine: &Machine) -> Result<Vec<u16>, AsmError> {
+    let mut labels: HashMap<String, u16> = HashMap::new();
+    let mut instructions: Vec<(usize, &str)> = Vec::new();
+
+    for (line, raw) in source.lines().enumerate() {
+        let mut text = strip_comment(raw).trim();
+        while let Some(colon) = text.find(':') {
+            let label = text[..colon].trim();
+            if !is_identifier(label) {
+                return Err(error(line, format!("invalid label '{}'", label)));
+            }
+            if labels.insert(label.to_string(), instructions.len() as u16).is_some() {
+                return Err(error(line, format!("duplicate label '{}'", label)));
+            }
+            text = text[colon + 1..].trim();
+        }
+        if !text.is_empty() {
+            instructions.push((line, text));
+        }
+    }
+
+    if let Some((line, _)) = instructions.get(machine.rom as usize) {
+        return Err(error(*line, format!("program does not fit in {} words of ROM", machine.rom)));
+    }
+
+    instructions.iter()
+        .map(|(line, text)| encode(*line, text, &labels, machine))
+        .collect()
+}
+
+/// Formats ROM words in the line-per-word binary format read by `Cpu::load_rom`.
+pub fn to_bin(words: &[u16]) -> String {
+    words.iter().map(|word| format!("{word:016b}\n")).collect()
+}
+
+fn strip_comment(line: &str) -> &str {
+    let end = [line.find(';'), line.find("//")].into_iter().flatten().min();
+    match end {
+        Some(end) => &line[..end],
+        None => line,
+    }
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        _ => false,
+    }
+}
+
+fn encode(line: usize, text: &str, labels: &HashMap<String, u16>, machine: &Machine) -> Result<u16, AsmError> {
+    let (mnemonic, rest) = match text.find(char::is_whitespace) {
+        Some(split) => (&text[..split], text[split..].trim()),
+        None => (text, ""),
+    };
+    let mnemonic = mnemonic.to_ascii_lowercase();
+    let operands: Vec<&str> = match rest.is_empty() {
+        true => Vec::new(),
+        false => rest.split(',').map(str::trim).collect(),
+    };
+
+    let forms: Vec<&Definition> = machine.isa.forms(&mnemonic).collect();
+    if forms.is_empty() {
+        return Err(error(line, format!("unknown mnemonic '{}'", mnemonic)));
+    }
+
+    let parser = Operands { line, operands: &operands, labels, machine };
+    let mut fitting: Vec<&Definition> = forms.iter().copied().filter(|form| form.operands.len() == operands.len()).collect();
+    // A joined operand written at full width picks its form even if an earlier one would take the number.
+    fitting.sort_by_key(|form| !parser.spells_joined(form));
+    let (first, rest) = fitting.split_first()
+        .ok_or_else(|| error(line, format!("'{}' takes {} operand(s), found {}", mnemonic, counts(&forms), operands.len())))?;
+
+    // When no form fits, the first one's complaint is the useful one.
+    parser.encode(first).or_else(|error| rest.iter().find_map(|form| parser.encode(form).ok()).ok_or(error))
+}
+
+/// The operand counts the forms accept, as `2` or `0, 1 or 2`.
+fn counts(forms: &[&Definition]) -> String {
+    let mut counts: Vec<usize> = forms.iter().map(|form| form.operands.len()).collect();
+    counts.sort();
+    counts.dedup();
+    let counts: Vec<String> = counts.iter().map(|count| count.to_string()).collect();
+    match counts.split_last() {
+        Some((last, rest)) if !rest.is_empty() => format!("{} or {}", rest.join(", "), last),
+        _ => counts.join(""),
+    }
+}
+
+struct Operands<'a> {
+    line: usize,
+    operands: &'a [&'a str],
+    labels: &'a HashMap<String, u16>,
+    machine: &'a Machine,
+}
+
+impl Operands<'_> {
+    /// Encodes the operands into `form`, which must take as many as there are.
+    fn encode(&self, form: &Definition) -> Result<u16, AsmError> {
+        let mut word = form.opcode;
+        for (idx, operand) in form.operands.iter().enumerate() {
+            let letters = match operand {
+                Operand::Fields(letters) => letters,
+                Operand::Ignored => continue,
+            };
+            let max = form.operand_max(letters);
+            let what = format!("{} operand", form.micro_op);
+            let value = match letters[..] {
+                [letter] => match Kind::of(letter) {
+                    Some(Kind::Register) => self.register(idx, max)?,
+                    Some(Kind::Condition) => self.condition(idx, max)?,
+                    Some(Kind::Data) => self.data_address(idx, max)?,
+                    Some(Kind::Target) => self.number(idx, max.min(self.machine.rom as u32 - 1), "jump target")?,
+                    Some(Kind::Immediate) => self.number(idx, max.min(255), "immediate")?,
+                    Some(Kind::Vector) => self.number(idx, max.min(15), "interrupt vector")?,
+                    Some(Kind::Argument) => self.number(idx, max.min(255), "interrupt argument")?,
+                    None => self.number(idx, max, &what)?,
+                },
+                _ => self.joined(idx, form.operand_width(letters), &what)?,
+            };
+            word |= form.place(letters, value);
+        }
+
+        // An extension placed ahead of the form may claim the word, such as `cal` taking `int 14`.
+        let decoded = self.machine.isa.definition(word);
+        match (decoded.mnemonic == form.mnemonic, decoded.micro_op == form.micro_op) {
+            (true, true) => Ok(word),
+            _ => Err(error(self.line, format!("word {:04x} decodes as '{}' on this machine, not as '{}'",
+                                              word, self.machine.format(word), form.mnemonic))),
+        }
+    }
+
+    /// Whether some operand of `form` that joins several fields is written as `joined` expects.
+    fn spells_joined(&self, form: &Definition) -> bool {
+        form.operands.iter().enumerate().any(|(idx, operand)| match operand {
+            Operand::Fields(letters) if letters.len() > 1 => joined_digits(self.operands[idx], form.operand_width(letters)).is_some(),
+            _ => false,
+        })
+    }
+
+    /// An operand joining several fields, written as `0x` and one hex digit per
+    /// four bits (`0x005` for 12 bits) so it cannot be mistaken for the first field alone.
+    fn joined(&self, idx: usize, width: u16, what: &str) -> Result<u16, AsmError> {
+        let text = self.operands[idx];
+        let digits = joined_digits(text, width)
+            .ok_or_else(|| error(self.line, format!("{} '{}' must be written as 0x and {} hex digits", what, text, width.div_ceil(4))))?;
+        match u16::from_str_radix(digits, 16) {
+            Ok(value) if (value as u32) < 1 << width => Ok(value),
+            _ => Err(error(self.line, format!("{} {} out of range (0-0x{:x})", what, text, (1u32 << width) - 1))),
+        }
+    }
+
+    fn value(&self, idx: usize) -> Result<u32, AsmError> {
+        let text = self.operands[idx];
+        if let Some(&address) = self.labels.get(text) {
+            return Ok(address as u32);
+        }
+        parse_number(text).ok_or_else(|| match is_identifier(text) {
+            true => error(self.line, format!("undefined label '{}'", text)),
+            false => error(self.line, format!("invalid number '{}'", text)),
+        })
+    }
+
+    fn number(&self, idx: usize, max: u32, what: &str) -> Result<u16, AsmError> {
+        let value = self.value(idx)?;
+        match value <= max {
+            true => Ok(value as u16),
+            false => Err(error(self.line, format!("{} {} out of range (0-{})", what, value, max))),
+        }
+    }
+
+    /// A RAM address, an I/O port name, or a raw port address (`IO_BASE`-255)
+    /// if the field reaches that far.
+    fn data_address(&self, idx: usize, max: u32) -> Result<u16, AsmError> {
+        let text = self.operands[idx].to_ascii_lowercase();
+        if let Some(port) = text.strip_prefix("inp").or_else(|| text.strip_prefix("out")) {
+            return match parse_number(port) {
+                Some(port @ 0..=7) if max >= 255 => Ok(IO_BASE as u16 + port as u16),
+                _ => Err(error(self.line, format!("invalid I/O port '{}'", self.operands[idx]))),
+            };
+        }
+        let value = self.value(idx)?;
+        let last = (self.machine.ram as u32 - 1).min(max);
+        match value <= last || (IO_BASE as u32..=max).contains(&value) {
+            true => Ok(value as u16),
+            false => Err(error(self.line, format!("RAM address {} out of range (0-{}, {}-255)", value, last, IO_BASE))),
+        }
+    }
+
+    fn register(&self, idx: usize, max: u32) -> Result<u16, AsmError> {
+        let text = self.operands[idx];
+        let number = text.strip_prefix(['r', 'R']).unwrap_or(text);
+        let value = parse_number(number)
+            .ok_or_else(|| error(self.line, format!("invalid register '{}'", text)))?;
+        let last = (self.machine.registers as u32 - 1).min(max);
+        match value <= last {
+            true => Ok(value as u16),
+            false => Err(error(self.line, format!("register {} out of range (0-{})", value, last))),
+        }
+    }
+
+    fn condition(&self, idx: usize, max: u32) -> Result<u16, AsmError> {
+        let text = self.operands[idx];
+        let max = max.min(15);
+        if let Some(flag) = FLAG_NAMES.iter().position(|name| name.eq_ignore_ascii_case(text)).filter(|&flag| flag as u32 <= max) {
+            return Ok(flag as u16);
+        }
+        let value = parse_number(text)
+            .ok_or_else(|| error(self.line, format!("unknown condition '{}'", text)))?;
+        match value <= max {
+            true => Ok(value as u16),
+            false => Err(error(self.line, format!("condition {} out of range (0-{})", value, max))),
+        }
+    }
+}
+
+/// The hex digits of `0x` followed by exactly one digit per four bits of `width`.
+fn joined_digits(text: &str, width: u16) -> Option<&str> {
+    text.strip_prefix("0x").or_else(|| text.strip_prefix("0X"))
+        .filter(|digits| digits.len() == width.div_ceil(4) as usize && digits.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Parses a decimal, `0x` hex or `0b` binary literal.
+pub(crate) fn parse_number(text: &str) -> Option<u32> {
+    parse_wide_number(text).and_then(|value| u32::try_from(value).ok())
+}
+
+/// `parse_number` for values that may not fit 32 bits, such as cycle counts.
+pub(crate) fn parse_wide_number(text: &str) -> Option<u64> {
+    let text = text.replace('_', "");
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = text.strip_prefix("0b").or_else(|| text.strip_prefix("0B")) {
+        u64::from_str_radix(bin, 2).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble_nano(source: &str) -> Result<Vec<u16>, AsmError> {
+        assemble(source, &Machine::default())
+    }
+
+    #[test]
+    fn assembles_program() {
+        let source = "\
+; count r1 down from 3, storing each value
+        imm r1, 3
+        imm 2, 1
+loop:   dms r1, 0x10        // RAM cell 16
+        sub r1, r1, r2
+        cmp r1, r0
+        brc NE, loop
+        dml 3, inp0
+        dms r3, out7
+        iml r4, r1
+        ims r1, r4
+        ibr TR, 0, r5
+        rsh r6, r1
+        and r1, r2, r3
+        nor r1, r2, r3
+        xor r1, r2, r3
+        add r1, r2, r3
+        int 2, 1
+done:   jmp done
+";
+        let words = [0x8103, 0x8201, 0xa110, 0x2112, 0x7010, 0xdd02, 0x93f8, 0xa3ff, 0xb410,
+                     0xc014, 0xef05, 0x6610, 0x3123, 0x4123, 0x5123, 0x1123, 0x0201, 0xf011];
+        assert_eq!(assemble_nano(source), Ok(words.to_vec()));
+    }
+
+    #[test]
+    fn reports_errors_with_line() {
+        let cases = [
+            ("imm r1, 1\nadd r8, r1, r1", 1, "register 8 out of range (0-7)"),
+            ("dml r1, 32", 0, "RAM address 32 out of range (0-31, 248-255)"),
+            ("\n\njmp 64", 2, "jump target 64 out of range (0-63)"),
+            ("imm r1, 1\nmov r1, r2", 1, "unknown mnemonic 'mov'"),
+            ("a: imm r1, 1\n\na: jmp a", 2, "duplicate label 'a'"),
+            ("jmp nowhere", 0, "undefined label 'nowhere'"),
+        ];
+        for (source, line, message) in cases {
+            assert_eq!(assemble_nano(source), Err(AsmError { line, message: message.to_string() }), "{}", source);
+        }
+    }
+}
@@ -0,0 +1,344 @@
+//! Running programs to completion without a terminal, for scripted regression tests.
+
+use std::{convert::Infallible, fmt, ops::Range, str::FromStr};
+
+use crate::{asm::{parse_number, parse_wide_number}, cpu::{Cpu, StackFault, Step, FLAG_NAMES}, interrupt::Interrupt, machine::{MAX_RAM, MAX_REGISTERS}, timing};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// Stopped on a `halt` interrupt.
+    Halted,
+    /// Stopped on an `input` interrupt with nothing left in the input queue.
+    WaitingForInput,
+    /// Stopped on a `cal` with the call stack full or a `ret` with it empty.
+    Fault(StackFault),
+    /// Still running when the cycle limit was reached.
+    CycleLimit,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Status::Halted => f.write_str("halted"),
+            Status::WaitingForInput => f.write_str("waiting for input"),
+            Status::Fault(fault) => write!(f, "{}", fault),
+            Status::CycleLimit => f.write_str("cycle limit"),
+        }
+    }
+}
+
+/// Steps `cpu` until it halts or has executed `max_cycles` more instructions.
+pub fn run(cpu: &mut Cpu, max_cycles: usize) -> Status {
+    run_with(cpu, max_cycles, |_, _| Ok::<_, Infallible>(())).unwrap_or_else(|never| match never {})
+}
+
+/// Like `run`, calling `on_step` after every instruction, e.g. to write a trace.
+/// Stops at the first error `on_step` returns.
+pub fn run_with<E>(cpu: &mut Cpu, max_cycles: usize, mut on_step: impl FnMut(&mut Cpu, &Step) -> Result<(), E>) -> Result<Status, E> {
+    for _ in 0..max_cycles {
+        let step = cpu.step();
+        on_step(cpu, &step)?;
+        if step.halted {
+            return Ok(match (step.interrupt, step.fault) {
+                (_, Some(fault)) => Status::Fault(fault),
+                (Some(Interrupt::Input { .. }), _) => Status::WaitingForInput,
+                _ => Status::Halted,
+            });
+        }
+    }
+    Ok(Status::CycleLimit)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Target {
+    Reg(u16),
+    Ram(Range<u16>),
+    Out(u16),
+    Flag(u16),
+    Pc,
+    Executed,
+    Halted,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Check {
+    /// Every selected cell holds this value.
+    Equals(u64),
+    /// The selected cells hold exactly these values, in order.
+    List(Vec<u64>),
+    Sorted,
+    SortedDesc,
+}
+
+/// A condition on the final machine state, such as `reg3=0x1f`,
+/// `ram[0..32]=sorted`, `ram[4]=1,2,3`, `out0=7`, `ZE=1`, `pc=17` or `halted`.
+/// Registers and RAM cells that the machine does not have fail the check.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Expectation {
+    text: String,
+    target: Target,
+    check: Check,
+}
+
+impl FromStr for Expectation {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid expectation '{}'", text);
+        let (lhs, rhs) = match text.split_once('=') {
+            Some((lhs, rhs)) => (lhs.trim().to_ascii_lowercase(), rhs.trim().to_ascii_lowercase()),
+            None if text.trim().eq_ignore_ascii_case("halted") => ("halted".to_string(), "1".to_string()),
+            None => return Err(invalid()),
+        };
+
+        let index = |text: &str, max: u32| parse_number(text).filter(|&n| n < max).map(|n| n as u16);
+        let target = if let Some(range) = lhs.strip_prefix("ram[").and_then(|rest| rest.strip_suffix(']')) {
+            match range.split_once("..") {
+                Some((start, end)) => {
+                    let start = index(start, MAX_RAM as u32).ok_or_else(invalid)?;
+                    let end = parse_number(end).filter(|&n| n <= MAX_RAM as u32 && n > start as u32).ok_or_else(invalid)?;
+                    Target::Ram(start..end as u16)
+                }
+                None => {
+                    let idx = index(range, MAX_RAM as u32).ok_or_else(invalid)?;
+                    Target::Ram(idx..idx + 1)
+                }
+            }
+        } else if let Some(idx) = lhs.strip_prefix("reg").or_else(|| lhs.strip_prefix('r')).and_then(|n| index(n, MAX_REGISTERS as u32)) {
+            Target::Reg(idx)
+        } else if let Some(idx) = lhs.strip_prefix("out").and_then(|n| index(n, 8)) {
+            Target::Out(idx)
+        } else if let Some(flag) = FLAG_NAMES.iter().position(|name| name.eq_ignore_ascii_case(&lhs)) {
+            Target::Flag(flag as u16)
+        } else {
+            match lhs.as_str() {
+                "pc" => Target::Pc,
+                "executed" | "executed_instructions" => Target::Executed,
+                "halted" => Target::Halted,
+                _ => return Err(invalid()),
+            }
+        };
+
+        let check = match rhs.as_str() {
+            "sorted" => Check::Sorted,
+            "sorted-desc" => Check::SortedDesc,
+            "true" => Check::Equals(1),
+            "false" => Check::Equals(0),
+            _ if rhs.contains(',') => Check::List(rhs.split(',')
+                .map(|n| parse_wide_number(n.trim()))
+                .collect::<Option<_>>()
+                .ok_or_else(invalid)?),
+            _ => Check::Equals(parse_wide_number(&rhs).ok_or_else(invalid)?),
+        };
+
+        // A value the target cannot hold would never match, so it is a typo.
+        let max = match target {
+            Target::Reg(_) | Target::Ram(_) | Target::Out(_) => u8::MAX as u64,
+            Target::Flag(_) | Target::Halted => 1,
+            Target::Pc | Target::Executed => u64::MAX,
+        };
+        let values = match &check {
+            Check::Equals(value) => std::slice::from_ref(value),
+            Check::List(list) => list.as_slice(),
+            Check::Sorted | Check::SortedDesc => &[],
+        };
+        if let Some(value) = values.iter().find(|&&value| value > max) {
+            return Err(format!("value {} out of range (0-{}) in expectation '{}'", value, max, text));
+        }
+
+        Ok(Expectation { text: text.trim().to_string(), target, check })
+    }
+}
+
+impl fmt::Display for Expectation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl Expectation {
+    /// Checks the expectation, returning the values actually found on mismatch.
+    pub fn check(&self, cpu: &Cpu, status: Status) -> Result<(), Vec<u64>> {
+        let values: Vec<u64> = match &self.target {
+            Target::Reg(idx) if (*idx as usize) < cpu.reg.len() => vec![cpu.reg[*idx as usize] as u64],
+            Target::Ram(range) if (range.end as usize) <= cpu.ram.len() => cpu.ram[range.start as usize..range.end as usize].iter().map(|&v| v as u64).collect(),
+            Target::Reg(_) | Target::Ram(_) => return Err(Vec::new()),
+            Target::Out(idx) => vec![cpu.out[*idx as usize] as u64],
+            Target::Flag(idx) => vec![cpu.flg[*idx as usize] as u64],
+            Target::Pc => vec![(cpu.pc % cpu.machine().rom) as u64],
+            Target::Executed => vec![cpu.executed_instructions as u64],
+            Target::Halted => vec![(status == Status::Halted) as u64],
+        };
+        let ok = match &self.check {
+            Check::Equals(value) => values.iter().all(|v| v == value),
+            Check::List(list) => &values == list,
+            Check::Sorted => values.windows(2).all(|w| w[0] <= w[1]),
+            Check::SortedDesc => values.windows(2).all(|w| w[0] >= w[1]),
+        };
+        match ok {
+            true => Ok(()),
+            false => Err(values),
+        }
+    }
+}
+
+fn hex_list(values: &[u8]) -> String {
+    values.iter().map(|v| format!("{v:02x}")).collect::<Vec<_>>().join(" ")
+}
+
+/// Escapes `text` for use inside a JSON string. ISA names come from file stems,
+/// so unlike machine names they may hold any character.
+fn json_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn json_list(values: &[u8]) -> String {
+    values.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(",")
+}
+
+/// Human-readable dump of the final machine state.
+pub fn text_report(cpu: &Cpu, status: Status) -> String {
+    let flags: Vec<String> = FLAG_NAMES.iter().zip(cpu.flg)
+        .map(|(name, flag)| format!("{}={}", name, flag as u8))
+        .collect();
+
+    let mut report = String::new();
+    report.push_str(&format!("machine: {}\n", cpu.machine()));
+    report.push_str(&format!("isa: {}\n", cpu.machine().isa.name));
+    report.push_str(&format!("status: {}\n", status));
+    report.push_str(&format!("executed_instructions: {}\n", cpu.executed_instructions));
+    report.push_str(&format!("elapsed_ticks: {}\n", cpu.elapsed_ticks));
+    report.push_str(&format!("simulated_time: {:.1} s\n", timing::seconds(cpu.elapsed_ticks)));
+    report.push_str(&format!("simulated_frequency: {:.3} Hz\n", timing::frequency(cpu.executed_instructions, cpu.elapsed_ticks)));
+    report.push_str(&format!("pc: {}\n", cpu.pc % cpu.machine().rom));
+    report.push_str(&format!("reg: {}\n", hex_list(&cpu.reg)));
+    for (row, chunk) in cpu.ram.chunks(8).enumerate() {
+        report.push_str(&format!("ram[{:02}]: {}\n", row * 8, hex_list(chunk)));
+    }
+    report.push_str(&format!("inp: {}\n", hex_list(&cpu.inp)));
+    report.push_str(&format!("out: {}\n", hex_list(&cpu.out)));
+    if cpu.machine().stack > 0 {
+        let stack = match cpu.sp {
+            0 => "-".to_string(),
+            _ => hex_list(&cpu.stack[..cpu.sp as usize]),
+        };
+        report.push_str(&format!("stack: {}\n", stack));
+    }
+    report.push_str(&format!("flags: {}\n", flags.join(" ")));
+
+    report
+}
+
+/// The same state as `text_report`, as a single JSON object.
+pub fn json_report(cpu: &Cpu, status: Status) -> String {
+    let flags: Vec<String> = FLAG_NAMES.iter().zip(cpu.flg)
+        .map(|(name, flag)| format!("\"{}\":{}", name, flag))
+        .collect();
+
+    // Only machines with a call stack report one, so stock reports keep their shape.
+    let stack = match cpu.machine().stack {
+        0 => String::new(),
+        _ => format!(",\"stack\":[{}]", json_list(&cpu.stack[..cpu.sp as usize])),
+    };
+    format!("{{\"machine\":\"{}\",\"isa\":\"{}\",\"status\":\"{}\",\"executed_instructions\":{},\"elapsed_ticks\":{},\"simulated_time\":{},\"simulated_frequency\":{},\"pc\":{},\"reg\":[{}],\"ram\":[{}],\"inp\":[{}],\"out\":[{}]{},\"flags\":{{{}}}}}\n",
+            cpu.machine().name, json_escape(&cpu.machine().isa.name), status, cpu.executed_instructions, cpu.elapsed_ticks,
+            timing::seconds(cpu.elapsed_ticks), timing::frequency(cpu.executed_instructions, cpu.elapsed_ticks), cpu.pc % cpu.machine().rom,
+            json_list(&cpu.reg), json_list(&cpu.ram), json_list(&cpu.inp), json_list(&cpu.out), stack,
+            flags.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Machine;
+
+    fn check(text: &str, cpu: &Cpu, status: Status) -> Result<(), Vec<u64>> {
+        text.parse::<Expectation>().unwrap().check(cpu, status)
+    }
+
+    #[test]
+    fn parses_expectations() {
+        let cases = [
+            ("reg3=0x1f", Target::Reg(3), Check::Equals(0x1f)),
+            ("r15 = 0b101", Target::Reg(15), Check::Equals(5)),
+            ("ram[0..32]=sorted", Target::Ram(0..32), Check::Sorted),
+            ("ram[4..7]=sorted-desc", Target::Ram(4..7), Check::SortedDesc),
+            ("ram[4]=1,2,3", Target::Ram(4..5), Check::List(vec![1, 2, 3])),
+            ("out0=7", Target::Out(0), Check::Equals(7)),
+            ("ze=true", Target::Flag(0), Check::Equals(1)),
+            ("US=0", Target::Flag(14), Check::Equals(0)),
+            ("pc=17", Target::Pc, Check::Equals(17)),
+            ("executed=5000000000", Target::Executed, Check::Equals(5_000_000_000)),
+            ("halted", Target::Halted, Check::Equals(1)),
+        ];
+        for (text, target, check) in cases {
+            assert_eq!(text.parse(), Ok(Expectation { text: text.to_string(), target, check }), "{}", text);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_expectations() {
+        let cases = [
+            ("reg3", "invalid expectation 'reg3'"),
+            ("reg16=1", "invalid expectation 'reg16=1'"),
+            ("ram[128]=1", "invalid expectation 'ram[128]=1'"),
+            ("ram[4..4]=sorted", "invalid expectation 'ram[4..4]=sorted'"),
+            ("out8=1", "invalid expectation 'out8=1'"),
+            ("sp=1", "invalid expectation 'sp=1'"),
+            ("reg3=x", "invalid expectation 'reg3=x'"),
+            ("reg3=65537", "value 65537 out of range (0-255) in expectation 'reg3=65537'"),
+            ("ram[0]=1,256", "value 256 out of range (0-255) in expectation 'ram[0]=1,256'"),
+            ("ZE=2", "value 2 out of range (0-1) in expectation 'ZE=2'"),
+            ("halted=2", "value 2 out of range (0-1) in expectation 'halted=2'"),
+        ];
+        for (text, message) in cases {
+            assert_eq!(text.parse::<Expectation>(), Err(message.to_string()), "{}", text);
+        }
+    }
+
+    #[test]
+    fn checks_final_state() {
+        let mut cpu = Cpu::new();
+        cpu.reg[3] = 0x1f;
+        cpu.ram[..4].copy_from_slice(&[1, 2, 2, 9]);
+        cpu.out[7] = 7;
+        cpu.pc = 17;
+        cpu.executed_instructions = 382;
+
+        for text in ["reg3=31", "ram[0..4]=sorted", "ram[0..4]=1,2,2,9", "ram[3]=9", "out7=7", "TR=1", "pc=17", "executed=382", "halted"] {
+            assert_eq!(check(text, &cpu, Status::Halted), Ok(()), "{}", text);
+        }
+        let cases = [
+            ("reg3=1", vec![0x1f]),
+            ("ram[0..4]=sorted-desc", vec![1, 2, 2, 9]),
+            ("ram[0..3]=1,2,9", vec![1, 2, 2]),
+            ("ZE=1", vec![0]),
+            // 382 + 65536: must not pass by truncation.
+            ("executed=65918", vec![382]),
+            // Cells the machine does not have.
+            ("reg8=0", vec![]),
+            ("ram[32]=0", vec![]),
+        ];
+        for (text, found) in cases {
+            assert_eq!(check(text, &cpu, Status::Halted), Err(found), "{}", text);
+        }
+        assert_eq!(check("halted", &cpu, Status::CycleLimit), Err(vec![0]));
+    }
+
+    #[test]
+    fn escapes_isa_name_in_json() {
+        let mut machine = Machine::default();
+        machine.isa.name = "a\"b\\c\td".to_string();
+        let report = json_report(&Cpu::with_machine(machine), Status::Halted);
+        assert!(report.contains(r#""isa":"a\"b\\c\u0009d","#), "{}", report);
+    }
+}
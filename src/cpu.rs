@@ -0,0 +1,517 @@
+use std::{collections::VecDeque, fmt};
+
+use crate::{alu::{self, Op}, instruction::Instruction, interrupt::{Handler, Interrupt, Vectors}, machine::Machine, timing::TickCosts};
+
+/// Names of the flags in `Cpu::flg`, in index order. `brc` and `ibr` select one by index.
+pub const FLAG_NAMES: [&str; 16] = ["ZE", "NZ", "CA", "NC", "OF", "NO", "EV", "OD",
+                                    "GR", "LE", "LS", "GE", "EQ", "NE", "US", "TR"];
+
+/// Data addresses from here up reach the I/O ports instead of RAM: loads read
+/// `inp[addr - IO_BASE]` and stores write `out[addr - IO_BASE]`. Addresses
+/// between the end of RAM and `IO_BASE` wrap around into RAM.
+pub const IO_BASE: u8 = 0xf8;
+
+/// A single state mutation performed by an instruction, with the value it replaced.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Write {
+    Reg { idx: u16, old: u8, new: u8 },
+    Ram { idx: u16, old: u8, new: u8 },
+    Out { idx: u16, old: u8, new: u8 },
+    /// A return address pushed into call stack slot `idx`.
+    Stack { idx: u16, old: u8, new: u8 },
+}
+
+/// A `cal` with the call stack full, or a `ret` with it empty. The machine
+/// stops on the instruction without executing it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StackFault {
+    Overflow,
+    Underflow,
+}
+
+impl fmt::Display for StackFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            StackFault::Overflow => "stack overflow",
+            StackFault::Underflow => "stack underflow",
+        })
+    }
+}
+
+/// Description of what one call to `Cpu::step` did.
+#[derive(Clone, Debug)]
+pub struct Step {
+    pub pc: u16,
+    pub word: u16,
+    pub write: Option<Write>,
+    /// Set for an `int` that needs the host's attention.
+    pub interrupt: Option<Interrupt>,
+    pub fault: Option<StackFault>,
+    /// The machine stopped: a `halt` interrupt, an `input` waiting for a byte, or a stack fault.
+    pub halted: bool,
+    /// False for an `input` still waiting for a byte or a stack fault, which
+    /// changed nothing and is not counted or charged. A waiting `input` runs
+    /// again when the machine resumes.
+    pub executed: bool,
+}
+
+#[derive(Debug)]
+pub struct LoadError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line + 1, self.message)
+    }
+}
+
+/// The memories are sized by the `Machine` the CPU was built for; their lengths
+/// never change afterwards.
+pub struct Cpu {
+    /// Program memory. Write it through `write_to_rom` (or `load_rom`) so the
+    /// decoded instruction cache stays in sync.
+    pub rom: Vec<u16>,
+    /// The data path is 8 bits wide: every register, RAM cell and port holds
+    /// one byte, and arithmetic wraps modulo 256 in every build profile.
+    pub ram: Vec<u8>,
+    /// Register 0 is an ordinary register. ALU results, immediates and loads
+    /// written to it are stored like any other, and the flags an instruction
+    /// sets do not depend on its destination.
+    pub reg: Vec<u8>,
+    pub inp: [u8; 8],
+    pub out: [u8; 8],
+    pub flg: [bool; 16],
+    pub pc: u16,
+    /// Call stack slots, as many as the machine's stack depth. Return
+    /// addresses fit a byte since ROM has at most 256 words.
+    pub stack: Vec<u8>,
+    /// Number of slots in use; `cal` pushes into `stack[sp]`.
+    pub sp: u16,
+
+    pub executed_instructions: usize,
+    /// Game time consumed so far, in redstone ticks charged by `tick_costs`.
+    pub elapsed_ticks: u64,
+    pub tick_costs: TickCosts,
+    /// Host handlers for the `int` vectors.
+    pub vectors: Vectors,
+    /// Bytes queued by the host for the `input` interrupt, oldest first.
+    pub input: VecDeque<u8>,
+
+    machine: Machine,
+    /// Decoded instructions, with whether their definition writes flags.
+    decoded: Vec<Option<(Instruction, bool)>>,
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cpu {
+    /// A stock AnPU Nano.
+    pub fn new() -> Self {
+        Self::with_machine(Machine::default())
+    }
+
+    pub fn with_machine(machine: Machine) -> Self {
+        Cpu {
+            rom: vec![0; machine.rom as usize],
+            ram: vec![0; machine.ram as usize],
+            reg: vec![0; machine.registers as usize],
+            inp: [0; 8],
+            out: [0; 8],
+            flg: [false, false, false, false, false, false, false, false,
+                false, false, false, false, false, false, false, true],
+            pc: 0,
+            stack: vec![0; machine.stack as usize],
+            sp: 0,
+
+            executed_instructions: 0,
+            elapsed_ticks: 0,
+            tick_costs: TickCosts::default(),
+            vectors: Vectors::default(),
+            input: VecDeque::new(),
+
+            decoded: vec![None; machine.rom as usize],
+            machine,
+        }
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    pub fn full_reset(&mut self) {
+        self.rom.fill(0);
+        self.decoded.fill(None);
+        self.program_reset();
+    }
+
+    pub fn program_reset(&mut self) {
+        self.ram.fill(0);
+        self.reg.fill(0);
+        self.inp = [0; 8];
+        self.out = [0; 8];
+        self.flg = [false; 16];
+        self.flg[15] = true;
+        self.pc = 0;
+        self.stack.fill(0);
+        self.sp = 0;
+
+        self.executed_instructions = 0;
+        self.elapsed_ticks = 0;
+    }
+
+    pub fn write_to_rom(&mut self, idx: u16, val: u16) {
+        let idx = (idx % self.machine.rom) as usize;
+        self.rom[idx] = val;
+        self.decoded[idx] = None;
+    }
+
+    pub fn read_from_rom(&self, idx: u16) -> u16 {
+        self.rom[(idx % self.machine.rom) as usize]
+    }
+
+    /// Decodes a word for this CPU's machine, without touching the cache.
+    pub fn decode(&self, word: u16) -> Instruction {
+        self.machine.decode(word)
+    }
+
+    /// The decoded instruction at a ROM address, decoding it on first use.
+    pub fn instruction(&mut self, idx: u16) -> Instruction {
+        self.decoded(idx).0
+    }
+
+    fn decoded(&mut self, idx: u16) -> (Instruction, bool) {
+        let idx = (idx % self.machine.rom) as usize;
+        *self.decoded[idx].get_or_insert_with(|| {
+            let word = self.rom[idx];
+            let definition = self.machine.isa.definition(word);
+            (definition.decode(word, &self.machine), definition.flags)
+        })
+    }
+
+    pub fn write_to_ram(&mut self, idx: u16, val: u8) -> Write {
+        let idx = idx % self.machine.ram;
+        let old = self.ram[idx as usize];
+        self.ram[idx as usize] = val;
+
+        Write::Ram { idx, old, new: val }
+    }
+
+    pub fn write_to_out(&mut self, idx: u16, val: u8) -> Write {
+        let idx = idx % 8;
+        let old = self.out[idx as usize];
+        self.out[idx as usize] = val;
+
+        Write::Out { idx, old, new: val }
+    }
+
+    /// Pushes a return address; the caller checks that the stack has room.
+    fn push(&mut self, val: u8) -> Write {
+        let idx = self.sp;
+        let old = self.stack[idx as usize];
+        self.stack[idx as usize] = val;
+        self.sp += 1;
+
+        Write::Stack { idx, old, new: val }
+    }
+
+    /// Reads a data address as `dml` and `iml` see it.
+    pub fn load(&self, addr: u8) -> u8 {
+        match addr >= IO_BASE {
+            true => self.inp[(addr - IO_BASE) as usize],
+            false => self.ram[(addr as u16 % self.machine.ram) as usize],
+        }
+    }
+
+    /// Writes a data address as `dms` and `ims` see it.
+    pub fn store(&mut self, addr: u8, val: u8) -> Write {
+        match addr >= IO_BASE {
+            true => self.write_to_out((addr - IO_BASE) as u16, val),
+            false => self.write_to_ram(addr as u16, val),
+        }
+    }
+
+    pub fn write_to_regs(&mut self, idx: u16, val: u8) -> Write {
+        let idx = idx % self.machine.registers;
+        let old = self.reg[idx as usize];
+        self.reg[idx as usize] = val;
+
+        Write::Reg { idx, old, new: val }
+    }
+
+    /// Executes the instruction at `pc` and reports what it changed.
+    pub fn step(&mut self) -> Step {
+        let pc = self.pc % self.machine.rom;
+        let word = self.read_from_rom(pc);
+        let (instruction, flags) = self.decoded(pc);
+
+        let mut write = None;
+        let mut interrupt = None;
+        let mut fault = None;
+        let mut halted = false;
+        let mut executed = true;
+
+        // The program counter is as wide as the ROM address, so falling off the end wraps to 0.
+        let next = (pc + 1) % self.machine.rom;
+
+        match instruction {
+            Instruction::Int { vector, argument } => {
+                let reg = argument as u16 % self.machine.registers;
+                match self.vectors.handler(vector) {
+                    Handler::Halt => {
+                        interrupt = Some(Interrupt::Halt);
+                        halted = true;
+                        self.pc = next;
+                    }
+                    Handler::Print => {
+                        interrupt = Some(Interrupt::Print { reg, value: self.reg[reg as usize] });
+                        self.pc = next;
+                    }
+                    Handler::Input => match self.input.pop_front() {
+                        Some(value) => {
+                            write = Some(self.write_to_regs(reg, value));
+                            self.pc = next;
+                        }
+                        None => {
+                            interrupt = Some(Interrupt::Input { reg });
+                            halted = true;
+                            executed = false;
+                        }
+                    },
+                    Handler::Trap => {
+                        interrupt = Some(Interrupt::Trap { code: argument });
+                        self.pc = next;
+                    }
+                    Handler::Ignore => self.pc = next,
+                }
+            }
+            Instruction::Add { dest, src_a, src_b } => {
+                write = Some(self.alu(Op::Add, dest, src_a, src_b, flags));
+
+                self.pc = next;
+            }
+            Instruction::Sub { dest, src_a, src_b } => {
+                write = Some(self.alu(Op::Sub, dest, src_a, src_b, flags));
+
+                self.pc = next;
+            }
+            Instruction::And { dest, src_a, src_b } => {
+                write = Some(self.alu(Op::And, dest, src_a, src_b, flags));
+
+                self.pc = next;
+            }
+            Instruction::Nor { dest, src_a, src_b } => {
+                write = Some(self.alu(Op::Nor, dest, src_a, src_b, flags));
+
+                self.pc = next;
+            }
+            Instruction::Xor { dest, src_a, src_b } => {
+                write = Some(self.alu(Op::Xor, dest, src_a, src_b, flags));
+
+                self.pc = next;
+            }
+            Instruction::Rsh { dest, src } => {
+                write = Some(self.alu(Op::Rsh, dest, src, src, flags));
+
+                self.pc = next;
+            }
+            Instruction::Cmp { src_a, src_b, signed } => {
+                if flags {
+                    let flags = alu::compare(self.reg[src_a as usize], self.reg[src_b as usize], signed);
+                    self.flg[8..].copy_from_slice(&flags);
+                }
+
+                self.pc = next;
+            }
+            Instruction::Imm { dest, value } => {
+                write = Some(self.write_to_regs(dest, value));
+
+                self.pc = next;
+            }
+            Instruction::Dml { dest, addr } => {
+                write = Some(self.write_to_regs(dest, self.load(addr)));
+
+                self.pc = next;
+            }
+            Instruction::Dms { src, addr } => {
+                write = Some(self.store(addr, self.reg[src as usize]));
+
+                self.pc = next;
+            }
+            Instruction::Iml { dest, ptr } => {
+                write = Some(self.write_to_regs(dest, self.load(self.reg[ptr as usize])));
+
+                self.pc = next;
+            }
+            Instruction::Ims { ptr, src } => {
+                write = Some(self.store(self.reg[ptr as usize], self.reg[src as usize]));
+
+                self.pc = next;
+            }
+            Instruction::Brc { cond, addr } => {
+                if self.flg[cond as usize] {
+                    self.pc = addr;
+                } else {
+                    self.pc = next;
+                }
+            }
+            Instruction::Ibr { cond, ptr } => {
+                if self.flg[cond as usize] {
+                    self.pc = self.reg[ptr as usize] as u16 % self.machine.rom;
+                } else {
+                    self.pc = next;
+                }
+            }
+            Instruction::Jmp { addr } => {
+                self.pc = addr;
+            }
+            Instruction::Cal { addr } => match self.sp < self.machine.stack {
+                true => {
+                    write = Some(self.push(next as u8));
+                    self.pc = addr;
+                }
+                false => {
+                    fault = Some(StackFault::Overflow);
+                    halted = true;
+                    executed = false;
+                }
+            },
+            Instruction::Ret => match self.sp {
+                0 => {
+                    fault = Some(StackFault::Underflow);
+                    halted = true;
+                    executed = false;
+                }
+                _ => {
+                    self.sp -= 1;
+                    self.pc = self.stack[self.sp as usize] as u16 % self.machine.rom;
+                }
+            },
+        }
+
+        if executed {
+            self.executed_instructions += 1;
+            self.elapsed_ticks += self.tick_costs.cost(&instruction) as u64;
+        }
+
+        Step { pc, word, write, interrupt, fault, halted, executed }
+    }
+
+    /// Runs `op` on two registers, sets flags 0-7 if `flags` and writes the result to `dest`.
+    fn alu(&mut self, op: Op, dest: u16, src_a: u16, src_b: u16, flags: bool) -> Write {
+        let output = alu::execute(op, self.reg[src_a as usize], self.reg[src_b as usize]);
+        if flags {
+            self.flg[..8].copy_from_slice(&output.flags(op));
+        }
+
+        self.write_to_regs(dest, output.result)
+    }
+
+    /// Parses a ROM image in the line-per-word binary format. Lines that are not
+    /// 16 characters long are skipped but still occupy their address. An image
+    /// with words beyond the machine's ROM is rejected before anything is written.
+    pub fn load_rom(&mut self, text: &str) -> Result<(), LoadError> {
+        let lines: Vec<String> = text.split('\n').map(|x| x.trim().to_string()).collect();
+        if let Some(idx) = (self.machine.rom as usize..lines.len()).find(|&idx| lines[idx].len() == 16) {
+            return Err(LoadError { line: idx, message: format!("program does not fit in {} words of ROM", self.machine.rom) });
+        }
+        for (idx, line) in lines.iter().enumerate() {
+            if line.len() == 16 {
+                match u16::from_str_radix(line, 2) {
+                    Ok(p) => self.write_to_rom(idx as u16, p),
+                    Err(_) => return Err(LoadError { line: idx, message: "corrupted word".to_string() }),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Parses a RAM preset, one 8-character binary byte per line. Like
+    /// `load_rom`, it rejects bytes beyond the machine's RAM up front.
+    pub fn load_ram(&mut self, text: &str) -> Result<(), LoadError> {
+        let lines: Vec<String> = text.split('\n').map(|x| x.trim().to_string()).collect();
+        if let Some(idx) = (self.machine.ram as usize..lines.len()).find(|&idx| lines[idx].len() == 8) {
+            return Err(LoadError { line: idx, message: format!("preset does not fit in {} bytes of RAM", self.machine.ram) });
+        }
+        for (idx, line) in lines.iter().enumerate() {
+            if line.len() == 8 {
+                match u8::from_str_radix(line, 2) {
+                    Ok(p) => {
+                        self.write_to_ram(idx as u16, p);
+                    }
+                    Err(_) => return Err(LoadError { line: idx, message: "corrupted word".to_string() }),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::isa::Isa;
+
+    fn stack_cpu(depth: u16, words: &[u16]) -> Cpu {
+        let mut cpu = Cpu::with_machine(Machine { stack: depth, isa: Isa::nano_with(true, false), ..Machine::default() });
+        for (idx, &word) in words.iter().enumerate() {
+            cpu.write_to_rom(idx as u16, word);
+        }
+        cpu
+    }
+
+    /// Steps once and checks that the step faulted without changing anything.
+    fn assert_faults(cpu: &mut Cpu, fault: StackFault) {
+        let (pc, sp, stack) = (cpu.pc, cpu.sp, cpu.stack.clone());
+        let (executed, ticks) = (cpu.executed_instructions, cpu.elapsed_ticks);
+
+        let step = cpu.step();
+        assert_eq!((step.fault, step.halted, step.executed, step.write), (Some(fault), true, false, None));
+        assert_eq!((cpu.pc, cpu.sp, &cpu.stack), (pc, sp, &stack));
+        assert_eq!((cpu.executed_instructions, cpu.elapsed_ticks), (executed, ticks));
+    }
+
+    #[test]
+    fn rejects_oversized_images() {
+        let mut cpu = Cpu::new();
+        let rom: String = (0..65).map(|idx| format!("{:016b}\n", 0x8100 + idx)).collect();
+        let ram: String = (0..64).map(|idx| format!("{:08b}\n", idx)).collect();
+
+        let error = cpu.load_rom(&rom).unwrap_err();
+        assert_eq!((error.line, error.message.as_str()), (64, "program does not fit in 64 words of ROM"));
+        let error = cpu.load_ram(&ram).unwrap_err();
+        assert_eq!((error.line, error.message.as_str()), (32, "preset does not fit in 32 bytes of RAM"));
+        assert!(cpu.rom.iter().all(|&word| word == 0) && cpu.ram.iter().all(|&byte| byte == 0));
+
+        cpu.load_rom(&rom[..64 * 17]).unwrap();
+        cpu.load_ram(&ram[..32 * 9]).unwrap();
+        assert_eq!((cpu.rom[63], cpu.ram[31]), (0x813f, 31));
+    }
+
+    #[test]
+    fn call_overflows_full_stack() {
+        // 0: cal 1, 1: cal 2, 2: cal 2
+        let mut cpu = stack_cpu(2, &[0x0e01, 0x0e02, 0x0e02]);
+        cpu.step();
+        cpu.step();
+        assert_eq!((cpu.pc, cpu.sp, cpu.stack.as_slice()), (2, 2, [1, 2].as_slice()));
+        assert_eq!(cpu.executed_instructions, 2);
+        assert_faults(&mut cpu, StackFault::Overflow);
+    }
+
+    #[test]
+    fn return_underflows_empty_stack() {
+        // 0: imm r1, 1, 1: ret
+        let mut cpu = stack_cpu(2, &[0x8101, 0x0f00]);
+        cpu.step();
+        assert_faults(&mut cpu, StackFault::Underflow);
+        assert_eq!(cpu.pc, 1);
+    }
+}
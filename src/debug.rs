@@ -0,0 +1,313 @@
+//! Breakpoints and watchpoints for stopping a running `Cpu`.
+
+use std::{collections::BTreeSet, fmt, str::FromStr};
+
+use crate::{asm::parse_number, cpu::{Cpu, Step, Write}, machine::{MAX_RAM, MAX_REGISTERS, MAX_STACK}};
+
+/// A register, RAM cell, output port or call stack slot that a watchpoint observes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Location {
+    Reg(u16),
+    Ram(u16),
+    Out(u16),
+    Stack(u16),
+}
+
+impl Location {
+    fn of(write: &Write) -> (Location, u8, u8) {
+        match *write {
+            Write::Reg { idx, old, new } => (Location::Reg(idx), old, new),
+            Write::Ram { idx, old, new } => (Location::Ram(idx), old, new),
+            Write::Out { idx, old, new } => (Location::Out(idx), old, new),
+            Write::Stack { idx, old, new } => (Location::Stack(idx), old, new),
+        }
+    }
+
+    /// Whether the location exists on `cpu`'s machine.
+    pub fn fits(&self, cpu: &Cpu) -> bool {
+        match *self {
+            Location::Reg(idx) => idx < cpu.machine().registers,
+            Location::Ram(idx) => idx < cpu.machine().ram,
+            Location::Out(idx) => idx < 8,
+            Location::Stack(idx) => idx < cpu.machine().stack,
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Location::Reg(idx) => write!(f, "r{}", idx),
+            Location::Ram(idx) => write!(f, "m{}", idx),
+            Location::Out(idx) => write!(f, "out{}", idx),
+            Location::Stack(idx) => write!(f, "s{}", idx),
+        }
+    }
+}
+
+/// Accepts `r3`/`reg3` for registers, `m12`/`ram12`/`ram[12]` for RAM, `out3` for output ports
+/// and `s2`/`stack2` for call stack slots.
+/// Indices are checked against the largest machine; `Location::fits` checks a particular one.
+impl FromStr for Location {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let text = text.trim().to_ascii_lowercase();
+        if let Some(number) = text.strip_prefix("out") {
+            return match parse_number(number) {
+                Some(idx @ 0..=7) => Ok(Location::Out(idx as u16)),
+                _ => Err(format!("invalid location '{}'", text)),
+            };
+        }
+        let (location, max, number): (fn(u16) -> Location, u16, &str) =
+            if let Some(n) = text.strip_prefix("ram").or_else(|| text.strip_prefix('m')) {
+                (Location::Ram, MAX_RAM, n)
+            } else if let Some(n) = text.strip_prefix("reg").or_else(|| text.strip_prefix('r')) {
+                (Location::Reg, MAX_REGISTERS, n)
+            } else if let Some(n) = text.strip_prefix("stack").or_else(|| text.strip_prefix('s')) {
+                (Location::Stack, MAX_STACK, n)
+            } else {
+                return Err(format!("unknown location '{}'", text));
+            };
+        let number = number.trim_start_matches('[').trim_end_matches(']');
+        match parse_number(number) {
+            Some(idx) if idx < max as u32 => Ok(location(idx as u16)),
+            _ => Err(format!("invalid location '{}'", text)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Condition {
+    /// Any write, even one that stores the value already there.
+    Any,
+    /// A write of exactly this value.
+    Equals(u8),
+    /// A write that moves the value from below the threshold to at or above it, or back.
+    Crosses(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub location: Location,
+    pub condition: Condition,
+}
+
+impl Watchpoint {
+    fn matches(&self, write: &Write) -> bool {
+        let (location, old, new) = Location::of(write);
+        if location != self.location {
+            return false;
+        }
+        match self.condition {
+            Condition::Any => true,
+            Condition::Equals(value) => new == value,
+            Condition::Crosses(threshold) => (old < threshold) != (new < threshold),
+        }
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.condition {
+            Condition::Any => write!(f, "{}", self.location),
+            Condition::Equals(value) => write!(f, "{} = {}", self.location, value),
+            Condition::Crosses(threshold) => write!(f, "{} cross {}", self.location, threshold),
+        }
+    }
+}
+
+/// Parses `<location>`, `<location> = <value>` or `<location> cross <threshold>`.
+impl FromStr for Watchpoint {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let value = |text: &str| parse_number(text.trim())
+            .and_then(|value| u8::try_from(value).ok())
+            .ok_or_else(|| format!("invalid value '{}'", text.trim()));
+
+        let (location, condition) = if let Some((location, rest)) = text.split_once('=') {
+            (location, Condition::Equals(value(rest)?))
+        } else if let Some((location, rest)) = text.split_once("cross") {
+            (location, Condition::Crosses(value(rest)?))
+        } else {
+            (text, Condition::Any)
+        };
+
+        Ok(Watchpoint { location: location.parse()?, condition })
+    }
+}
+
+/// Why execution stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Break {
+    Breakpoint(u16),
+    Watchpoint { location: Location, old: u8, new: u8 },
+}
+
+impl fmt::Display for Break {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Break::Breakpoint(pc) => write!(f, "Break at {:02}", pc),
+            Break::Watchpoint { location, old, new } => write!(f, "Watch {}: {:02x}->{:02x}", location, old, new),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    watchpoints: Vec<Watchpoint>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Toggles a breakpoint on a ROM address and returns whether it is now set.
+    pub fn toggle_breakpoint(&mut self, addr: u16) -> bool {
+        if !self.breakpoints.remove(&addr) {
+            self.breakpoints.insert(addr);
+            return true;
+        }
+        false
+    }
+
+    pub fn is_breakpoint(&self, addr: u16) -> bool {
+        self.breakpoints.contains(&addr)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+        self.watchpoints.clear();
+    }
+
+    /// Checked before executing the next instruction.
+    pub fn check_pc(&self, cpu: &Cpu) -> Option<Break> {
+        let pc = cpu.pc % cpu.machine().rom;
+        match self.is_breakpoint(pc) {
+            true => Some(Break::Breakpoint(pc)),
+            false => None,
+        }
+    }
+
+    /// Checked after an instruction executes, against the write it performed.
+    pub fn check_write(&self, step: &Step) -> Option<Break> {
+        let write = step.write?;
+        self.watchpoints.iter().find(|w| w.matches(&write)).map(|_| {
+            let (location, old, new) = Location::of(&write);
+            Break::Watchpoint { location, old, new }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_locations() {
+        let cases = [
+            ("r3", Location::Reg(3)),
+            ("reg15", Location::Reg(15)),
+            ("m12", Location::Ram(12)),
+            ("ram12", Location::Ram(12)),
+            (" RAM[0x7f] ", Location::Ram(127)),
+            ("out7", Location::Out(7)),
+            ("s2", Location::Stack(2)),
+            ("stack15", Location::Stack(15)),
+        ];
+        for (text, location) in cases {
+            assert_eq!(text.parse(), Ok(location), "{}", text);
+            assert_eq!(location.to_string().parse(), Ok(location), "{}", location);
+        }
+
+        let errors = [
+            ("R16", "invalid location 'r16'"),
+            ("m128", "invalid location 'm128'"),
+            ("out8", "invalid location 'out8'"),
+            ("s16", "invalid location 's16'"),
+            ("r", "invalid location 'r'"),
+            ("sp", "invalid location 'sp'"),
+            ("x1", "unknown location 'x1'"),
+        ];
+        for (text, message) in errors {
+            assert_eq!(text.parse::<Location>(), Err(message.to_string()), "{}", text);
+        }
+    }
+
+    #[test]
+    fn parses_watchpoints() {
+        let cases = [
+            ("r3", Location::Reg(3), Condition::Any),
+            ("m4 = 0x10", Location::Ram(4), Condition::Equals(16)),
+            ("out0 cross 128", Location::Out(0), Condition::Crosses(128)),
+            ("s1=0", Location::Stack(1), Condition::Equals(0)),
+        ];
+        for (text, location, condition) in cases {
+            let watchpoint = Watchpoint { location, condition };
+            assert_eq!(text.parse(), Ok(watchpoint), "{}", text);
+            assert_eq!(watchpoint.to_string().parse(), Ok(watchpoint), "{}", watchpoint);
+        }
+
+        let errors = [
+            ("r3 = 256", "invalid value '256'"),
+            ("r3 cross x", "invalid value 'x'"),
+            ("r3 =", "invalid value ''"),
+            ("q = 1", "unknown location 'q'"),
+        ];
+        for (text, message) in errors {
+            assert_eq!(text.parse::<Watchpoint>(), Err(message.to_string()), "{}", text);
+        }
+    }
+
+    #[test]
+    fn matches_writes() {
+        let reg = |old, new| Write::Reg { idx: 3, old, new };
+        let any: Watchpoint = "r3".parse().unwrap();
+        let equals: Watchpoint = "r3 = 10".parse().unwrap();
+        let cross: Watchpoint = "r3 cross 10".parse().unwrap();
+
+        // (write, any, equals, cross)
+        let cases = [
+            (reg(0, 0), true, false, false),
+            (reg(10, 10), true, true, false),
+            (reg(0, 10), true, true, true),
+            (reg(9, 10), true, true, true),
+            (reg(10, 9), true, false, true),
+            (reg(255, 0), true, false, true),
+            (reg(10, 255), true, false, false),
+            (reg(0, 9), true, false, false),
+            (Write::Ram { idx: 3, old: 0, new: 10 }, false, false, false),
+            (Write::Reg { idx: 4, old: 0, new: 10 }, false, false, false),
+        ];
+        for (write, any_hit, equals_hit, cross_hit) in cases {
+            assert_eq!(any.matches(&write), any_hit, "{:?}", write);
+            assert_eq!(equals.matches(&write), equals_hit, "{:?}", write);
+            assert_eq!(cross.matches(&write), cross_hit, "{:?}", write);
+        }
+
+        // A threshold of 0 can never be crossed: no value is below it.
+        let never: Watchpoint = "r3 cross 0".parse().unwrap();
+        assert!(!never.matches(&reg(255, 0)));
+    }
+}
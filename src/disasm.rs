@@ -0,0 +1,36 @@
+//! Turns ROM words back into assembler source.
+//!
+//! The listing is itself valid input for `asm::assemble`: every line carries the
+//! address, hex word and binary word in a trailing comment, and `brc`/`jmp`
+//! targets get synthesized `Lnn` labels.
+
+use std::collections::BTreeSet;
+
+use crate::machine::Machine;
+
+fn label(addr: u16) -> String {
+    format!("L{addr:02}")
+}
+
+/// Disassembles `rom` as code for `machine`, stopping after the last word
+/// that is non-zero or a jump target.
+pub fn disassemble(rom: &[u16], machine: &Machine) -> String {
+    let instructions: Vec<_> = rom.iter().map(|&word| machine.decode(word)).collect();
+    let targets: BTreeSet<u16> = instructions.iter().filter_map(|i| i.target()).collect();
+
+    let last_word = rom.iter().rposition(|&word| word != 0).unwrap_or(0);
+    let last_target = targets.iter().next_back().map_or(0, |&addr| addr as usize);
+    let end = (last_word.max(last_target) + 1).min(rom.len());
+
+    let mut listing = String::new();
+    for (addr, &word) in rom.iter().enumerate().take(end) {
+        let name = match targets.contains(&(addr as u16)) {
+            true => format!("{}:", label(addr as u16)),
+            false => String::new(),
+        };
+        let text = machine.format_with(word, label);
+        listing.push_str(&format!("{name:<8}{text:<20}; {addr:02}  {word:04x}  {word:016b}\n"));
+    }
+
+    listing
+}
@@ -0,0 +1,135 @@
+//! Bounded execution history for stepping a `Cpu` backwards.
+//!
+//! Each record holds only the pre-state delta of one instruction: the program
+//! counter, the flags, the call stack pointer, and the register, RAM cell or
+//! stack slot it overwrote. At a dozen bytes per record, millions of steps fit
+//! comfortably in memory.
+
+use std::collections::VecDeque;
+
+use crate::cpu::{Cpu, Step, Write};
+
+#[derive(Clone, Copy, Debug)]
+pub struct Record {
+    pub pc: u16,
+    pub flg: u16,
+    pub sp: u16,
+    pub write: Option<Write>,
+}
+
+pub struct History {
+    records: VecDeque<Record>,
+    capacity: usize,
+}
+
+pub(crate) fn pack_flags(flg: &[bool; 16]) -> u16 {
+    flg.iter().enumerate().fold(0, |acc, (idx, &flag)| acc | (flag as u16) << idx)
+}
+
+pub(crate) fn unpack_flags(bits: u16) -> [bool; 16] {
+    std::array::from_fn(|idx| bits >> idx & 1 != 0)
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        History { records: VecDeque::new(), capacity }
+    }
+
+    /// Steps `cpu` once, remembering enough to undo it. The oldest record is
+    /// dropped once the history is full; a step that did not execute leaves
+    /// nothing to undo and is not recorded.
+    pub fn step(&mut self, cpu: &mut Cpu) -> Step {
+        let pc = cpu.pc;
+        let flg = pack_flags(&cpu.flg);
+        let sp = cpu.sp;
+        let step = cpu.step();
+
+        if self.capacity > 0 && step.executed {
+            if self.records.len() == self.capacity {
+                self.records.pop_front();
+            }
+            self.records.push_back(Record { pc, flg, sp, write: step.write });
+        }
+
+        step
+    }
+
+    /// Reverts the most recent instruction, returning what was undone.
+    pub fn undo(&mut self, cpu: &mut Cpu) -> Option<Record> {
+        let record = self.records.pop_back()?;
+
+        cpu.pc = record.pc;
+        cpu.flg = unpack_flags(record.flg);
+        cpu.sp = record.sp;
+        match record.write {
+            Some(Write::Reg { idx, old, .. }) => cpu.reg[idx as usize] = old,
+            Some(Write::Ram { idx, old, .. }) => cpu.ram[idx as usize] = old,
+            Some(Write::Out { idx, old, .. }) => cpu.out[idx as usize] = old,
+            Some(Write::Stack { idx, old, .. }) => cpu.stack[idx as usize] = old,
+            None => {}
+        }
+        cpu.executed_instructions -= 1;
+        let instruction = cpu.instruction(record.pc);
+        cpu.elapsed_ticks = cpu.elapsed_ticks.saturating_sub(cpu.tick_costs.cost(&instruction) as u64);
+
+        Some(record)
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, isa::Isa, machine::Machine};
+
+    #[test]
+    fn undo_restores_every_step() {
+        let machine = Machine { stack: 2, isa: Isa::nano_with(true, false), ..Machine::default() };
+        let source = "\
+        imm r1, 3
+loop:   cal body
+        sub r1, r1, r2
+        cmp r1, r0
+        brc NE, loop
+        int
+body:   imm r2, 1
+        dms r1, 5
+        dms r1, out1
+        add r3, r3, r1
+        ret
+";
+        let mut cpu = Cpu::with_machine(machine.clone());
+        for (idx, word) in assemble(source, &machine).unwrap().into_iter().enumerate() {
+            cpu.write_to_rom(idx as u16, word);
+        }
+        let mut history = History::new(100);
+
+        // A save state covers everything undo restores: memories, pc, flags, stack and counters.
+        let mut snapshots = Vec::new();
+        loop {
+            snapshots.push(cpu.save_state());
+            if history.step(&mut cpu).halted {
+                break;
+            }
+        }
+        assert_eq!(history.len(), snapshots.len());
+        assert_eq!((cpu.reg[3], cpu.ram[5], cpu.out[1]), (6, 1, 1));
+
+        while let Some(expected) = snapshots.pop() {
+            history.undo(&mut cpu).unwrap();
+            assert_eq!(cpu.save_state(), expected, "undo back to step {}", snapshots.len());
+        }
+        assert!(history.undo(&mut cpu).is_none());
+    }
+}
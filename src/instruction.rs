@@ -0,0 +1,47 @@
+use crate::machine::Machine;
+
+/// A decoded ROM word: the micro-op an `isa` definition chose, with its
+/// operands already reduced to the range the machine actually uses (registers
+/// `% 8`, ROM addresses `% 64` and RAM addresses `% 32` on the stock build).
+/// Port addresses from `IO_BASE` up are kept whole so that they stay
+/// distinguishable from RAM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    /// Interrupt `vector` with an `argument` byte; see `interrupt`.
+    Int { vector: u16, argument: u8 },
+    Add { dest: u16, src_a: u16, src_b: u16 },
+    Sub { dest: u16, src_a: u16, src_b: u16 },
+    And { dest: u16, src_a: u16, src_b: u16 },
+    Nor { dest: u16, src_a: u16, src_b: u16 },
+    Xor { dest: u16, src_a: u16, src_b: u16 },
+    Rsh { dest: u16, src: u16 },
+    /// `compare`, or `compare-signed` when `signed`.
+    Cmp { src_a: u16, src_b: u16, signed: bool },
+    Imm { dest: u16, value: u8 },
+    Dml { dest: u16, addr: u8 },
+    Dms { src: u16, addr: u8 },
+    Iml { dest: u16, ptr: u16 },
+    Ims { ptr: u16, src: u16 },
+    Brc { cond: u16, addr: u16 },
+    Ibr { cond: u16, ptr: u16 },
+    Jmp { addr: u16 },
+    /// Pushes the address after it onto the call stack and jumps to `addr`.
+    Cal { addr: u16 },
+    /// Pops a return address off the call stack into the program counter.
+    Ret,
+}
+
+/// Decodes a 16-bit ROM word for the stock AnPU Nano.
+pub fn decode(word: u16) -> Instruction {
+    Machine::default().decode(word)
+}
+
+impl Instruction {
+    /// ROM address this instruction may jump to, if it is encoded in the word.
+    pub fn target(&self) -> Option<u16> {
+        match *self {
+            Instruction::Brc { addr, .. } | Instruction::Jmp { addr } | Instruction::Cal { addr } => Some(addr),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! Interrupts: `int` hands control to the host instead of dedicated I/O hardware.
+//!
+//! The 12-bit operand of `int` holds a vector in bits 8-11 and an argument in
+//! bits 0-7. Each of the 16 vectors is mapped to a host-side handler:
+//!
+//! | handler  | default vectors | effect                                                         |
+//! |----------|-----------------|----------------------------------------------------------------|
+//! | `halt`   | 0, 1, 5-15      | stops the machine                                              |
+//! | `print`  | 2               | reports register `argument` to the host                        |
+//! | `input`  | 3               | moves the next queued host byte into register `argument`       |
+//! | `trap`   | 4               | reports trap code `argument`; debuggers stop, batch runs go on |
+//! | `ignore` | -               | does nothing                                                   |
+//!
+//! Register arguments wrap to the machine's register count (`% 8` on the stock build).
+//!
+//! A bare `int` and the `int 0x100` that ends the bubble sort both halt.
+//! An `input` with an empty queue stops the machine on the `int` itself; once
+//! the host queues a byte, resuming executes the `int` again and it completes.
+//! Only the completed `int` counts as an executed instruction and is charged ticks.
+//! Stepping back over a completed `input` does not return its byte to the queue.
+
+use std::{fmt, str::FromStr};
+
+use crate::asm::parse_number;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Handler {
+    Halt,
+    Print,
+    Input,
+    Trap,
+    Ignore,
+}
+
+impl fmt::Display for Handler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Handler::Halt => "halt",
+            Handler::Print => "print",
+            Handler::Input => "input",
+            Handler::Trap => "trap",
+            Handler::Ignore => "ignore",
+        })
+    }
+}
+
+impl FromStr for Handler {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "halt" => Ok(Handler::Halt),
+            "print" => Ok(Handler::Print),
+            "input" => Ok(Handler::Input),
+            "trap" => Ok(Handler::Trap),
+            "ignore" => Ok(Handler::Ignore),
+            _ => Err(format!("unknown interrupt handler '{}'", text.trim())),
+        }
+    }
+}
+
+/// The handler behind each of the 16 interrupt vectors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Vectors {
+    handlers: [Handler; 16],
+}
+
+impl Default for Vectors {
+    fn default() -> Self {
+        let mut handlers = [Handler::Halt; 16];
+        handlers[2] = Handler::Print;
+        handlers[3] = Handler::Input;
+        handlers[4] = Handler::Trap;
+        Vectors { handlers }
+    }
+}
+
+impl Vectors {
+    pub fn handler(&self, vector: u16) -> Handler {
+        self.handlers[(vector % 16) as usize]
+    }
+
+    pub fn set(&mut self, vector: u16, handler: Handler) {
+        self.handlers[(vector % 16) as usize] = handler;
+    }
+}
+
+/// Parses comma-separated remappings such as `5=print,6=trap`; vectors that
+/// are not mentioned keep their default handler.
+impl FromStr for Vectors {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut vectors = Vectors::default();
+        for entry in text.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (vector, handler) = entry.split_once('=').ok_or_else(|| format!("invalid vector mapping '{}'", entry))?;
+            let vector = parse_number(vector.trim())
+                .filter(|&vector| vector < 16)
+                .ok_or_else(|| format!("invalid interrupt vector '{}'", vector.trim()))?;
+            vectors.set(vector as u16, handler.parse()?);
+        }
+
+        Ok(vectors)
+    }
+}
+
+/// What an `int` asks of the host, reported in `Step::interrupt`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+    Halt,
+    Print { reg: u16, value: u8 },
+    /// The input queue was empty; the machine waits on the `int` for a byte for `reg`.
+    Input { reg: u16 },
+    Trap { code: u8 },
+}
+
+impl fmt::Display for Interrupt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Interrupt::Halt => f.write_str("halt"),
+            Interrupt::Print { reg, value } => write!(f, "print r{} = {:02x} ({})", reg, value, value),
+            Interrupt::Input { reg } => write!(f, "input r{}", reg),
+            Interrupt::Trap { code } => write!(f, "trap {}", code),
+        }
+    }
+}
+
+/// Parses a comma-separated list of input bytes, e.g. `5,0x10,0b11`.
+pub fn parse_input(text: &str) -> Result<Vec<u8>, String> {
+    text.split(',').map(str::trim).filter(|value| !value.is_empty())
+        .map(|value| parse_number(value)
+            .and_then(|value| u8::try_from(value).ok())
+            .ok_or_else(|| format!("invalid input byte '{}'", value)))
+        .collect()
+}
@@ -0,0 +1,650 @@
+//! Instruction sets as data: one table drives the decoder, the executor, the
+//! assembler and the disassembler.
+//!
+//! Each line defines one instruction form:
+//!
+//! ```text
+//! # mnemonic  pattern              micro-op         flags  operands
+//! add         0001 dddd aaaa bbbb  add              flags  d a b
+//! cms         0111 ---1 aaaa bbbb  compare-signed   flags  a b
+//! brc         1101 cccc tttt tttt  branch           -      c t
+//! ```
+//!
+//! The pattern lists the 16 bits of the word from the top: `0` and `1` must
+//! match, `-` is ignored (and assembled as 0), and a run of one letter is an
+//! operand field. The letter says what the field holds:
+//!
+//! | letter          | field                                      |
+//! |-----------------|--------------------------------------------|
+//! | `d` `a` `b` `s` `p` | destination, sources, stored value, pointer register |
+//! | `i`             | immediate byte                             |
+//! | `m`             | data address: RAM, or a port from `IO_BASE` |
+//! | `t`             | ROM address to jump to                     |
+//! | `c`             | branch condition (flag index)              |
+//! | `v` `n`         | interrupt vector and argument              |
+//!
+//! The micro-op gives the semantics, and reads the fields it needs; a field
+//! it needs that the pattern leaves out reads as 0:
+//!
+//! | micro-op                               | fields  |
+//! |----------------------------------------|---------|
+//! | `add` `sub` `and` `nor` `xor`          | `d a b` |
+//! | `shift-right`                          | `d a`   |
+//! | `compare` `compare-signed`             | `a b`   |
+//! | `load-immediate`                       | `d i`   |
+//! | `load` / `store`                       | `d m` / `s m` |
+//! | `load-indirect` / `store-indirect`     | `d p` / `p s` |
+//! | `branch` / `branch-indirect` / `jump`  | `c t` / `c p` / `t` |
+//! | `interrupt`                            | `v n`   |
+//! | `call` / `return`                      | `t` / -  |
+//!
+//! `call` pushes the address after it onto the machine's call stack and jumps;
+//! `return` pops that address back into the program counter (see `cpu`).
+//!
+//! `flags` lets an ALU or compare micro-op set its flags (see `alu`); `-`
+//! leaves them alone. The operands give the assembler syntax in order: a
+//! field letter, several letters read as one number (`vn`, written in hex at
+//! full width such as `0x005`), or `_` for an operand that is accepted and
+//! ignored.
+//!
+//! A mnemonic may have several forms. The first form whose pattern matches
+//! decodes a word, and the first whose operands fit assembles a line, so
+//! narrower forms go first. Every word must match some form; a catch-all
+//! such as `und ---- ---- ---- ---- interrupt -` halts on the rest. Every form
+//! must also decode some word, unless it repeats an earlier pattern of the same
+//! mnemonic to accept another operand syntax.
+
+use std::{fmt, str::FromStr, sync::OnceLock};
+
+use crate::{cpu::{FLAG_NAMES, IO_BASE}, instruction::Instruction, machine::Machine};
+
+/// The stock AnPU Nano instruction set.
+pub const NANO: &str = "\
+# mnemonic  pattern              micro-op         flags  operands
+int         0000 0000 0000 0000  interrupt        -
+int         0000 vvvv 0000 0000  interrupt        -      v
+int         0000 vvvv nnnn nnnn  interrupt        -      v n
+# Older listings print the raw 12-bit operand, as `int 0x005`.
+int         0000 vvvv nnnn nnnn  interrupt        -      vn
+add         0001 dddd aaaa bbbb  add              flags  d a b
+sub         0010 dddd aaaa bbbb  sub              flags  d a b
+and         0011 dddd aaaa bbbb  and              flags  d a b
+nor         0100 dddd aaaa bbbb  nor              flags  d a b
+xor         0101 dddd aaaa bbbb  xor              flags  d a b
+rsh         0110 dddd aaaa ----  shift-right      flags  d a
+cmp         0111 ---- aaaa bbbb  compare          flags  a b
+imm         1000 dddd iiii iiii  load-immediate   -      d i
+dml         1001 dddd mmmm mmmm  load             -      d m
+dms         1010 ssss mmmm mmmm  store            -      s m
+iml         1011 dddd pppp ----  load-indirect    -      d p
+ims         1100 ---- pppp ssss  store-indirect   -      p s
+brc         1101 cccc tttt tttt  branch           -      c t
+ibr         1110 cccc ---- pppp  branch-indirect  -      c p
+# Older logs print `ibr cond, 0, ptr`.
+ibr         1110 cccc ---- pppp  branch-indirect  -      c _ p
+jmp         1111 tttt tttt tttt  jump             -      t
+";
+
+/// The call stack extension, placed ahead of the stock table on machines that
+/// have a stack. It takes over interrupt vectors 14 and 15.
+pub const STACK: &str = "\
+# mnemonic  pattern              micro-op         flags  operands
+cal         0000 1110 tttt tttt  call             -      t
+ret         0000 1111 ---- ----  return           -
+";
+
+/// The signed compare extension, placed ahead of the stock table on machines
+/// that enable it. It takes over the `cmp` words with bit 8 set, which stock
+/// `cmp` ignores.
+pub const SIGNED_COMPARE: &str = "\
+# mnemonic  pattern              micro-op         flags  operands
+cms         0111 ---1 aaaa bbbb  compare-signed   flags  a b
+";
+
+/// The library of micro-ops an instruction form can choose its semantics from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MicroOp {
+    Interrupt,
+    Add,
+    Sub,
+    And,
+    Nor,
+    Xor,
+    ShiftRight,
+    Compare,
+    CompareSigned,
+    LoadImmediate,
+    Load,
+    Store,
+    LoadIndirect,
+    StoreIndirect,
+    Branch,
+    BranchIndirect,
+    Jump,
+    Call,
+    Return,
+}
+
+const MICRO_OPS: [(MicroOp, &str, &str); 19] = [
+    (MicroOp::Interrupt, "interrupt", "vn"),
+    (MicroOp::Add, "add", "dab"),
+    (MicroOp::Sub, "sub", "dab"),
+    (MicroOp::And, "and", "dab"),
+    (MicroOp::Nor, "nor", "dab"),
+    (MicroOp::Xor, "xor", "dab"),
+    (MicroOp::ShiftRight, "shift-right", "da"),
+    (MicroOp::Compare, "compare", "ab"),
+    (MicroOp::CompareSigned, "compare-signed", "ab"),
+    (MicroOp::LoadImmediate, "load-immediate", "di"),
+    (MicroOp::Load, "load", "dm"),
+    (MicroOp::Store, "store", "sm"),
+    (MicroOp::LoadIndirect, "load-indirect", "dp"),
+    (MicroOp::StoreIndirect, "store-indirect", "ps"),
+    (MicroOp::Branch, "branch", "ct"),
+    (MicroOp::BranchIndirect, "branch-indirect", "cp"),
+    (MicroOp::Jump, "jump", "t"),
+    (MicroOp::Call, "call", "t"),
+    (MicroOp::Return, "return", ""),
+];
+
+impl MicroOp {
+    fn entry(&self) -> &'static (MicroOp, &'static str, &'static str) {
+        MICRO_OPS.iter().find(|(op, ..)| op == self).unwrap()
+    }
+
+    /// Field letters the micro-op reads.
+    pub fn fields(&self) -> &'static str {
+        self.entry().2
+    }
+
+    /// Whether the micro-op produces flags that `flags` can write.
+    pub fn has_flags(&self) -> bool {
+        matches!(self, MicroOp::Add | MicroOp::Sub | MicroOp::And | MicroOp::Nor | MicroOp::Xor
+                     | MicroOp::ShiftRight | MicroOp::Compare | MicroOp::CompareSigned)
+    }
+}
+
+impl fmt::Display for MicroOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.entry().1)
+    }
+}
+
+impl FromStr for MicroOp {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        MICRO_OPS.iter()
+            .find(|(_, name, _)| name.eq_ignore_ascii_case(text))
+            .map(|&(op, ..)| op)
+            .ok_or_else(|| format!("unknown micro-op '{}'", text))
+    }
+}
+
+/// What an operand field holds, which decides how it is reduced, printed and assembled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Register,
+    Immediate,
+    Data,
+    Target,
+    Condition,
+    Vector,
+    Argument,
+}
+
+impl Kind {
+    pub fn of(letter: char) -> Option<Kind> {
+        match letter {
+            'd' | 'a' | 'b' | 's' | 'p' => Some(Kind::Register),
+            'i' => Some(Kind::Immediate),
+            'm' => Some(Kind::Data),
+            't' => Some(Kind::Target),
+            'c' => Some(Kind::Condition),
+            'v' => Some(Kind::Vector),
+            'n' => Some(Kind::Argument),
+            _ => None,
+        }
+    }
+}
+
+/// An operand field: `width` bits starting at bit `shift`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Field {
+    pub letter: char,
+    pub shift: u16,
+    pub width: u16,
+}
+
+impl Field {
+    pub fn max(&self) -> u16 {
+        ((1u32 << self.width) - 1) as u16
+    }
+}
+
+/// One assembler operand: one or more fields read as a single number, or an ignored placeholder.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Operand {
+    Fields(Vec<char>),
+    Ignored,
+}
+
+/// One instruction form.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Definition {
+    pub mnemonic: String,
+    /// The fixed bits of the pattern, and which bits are fixed.
+    pub opcode: u16,
+    pub mask: u16,
+    pub micro_op: MicroOp,
+    /// Whether the form writes the flags its micro-op produces.
+    pub flags: bool,
+    pub fields: Vec<Field>,
+    pub operands: Vec<Operand>,
+}
+
+impl Definition {
+    pub fn matches(&self, word: u16) -> bool {
+        word & self.mask == self.opcode
+    }
+
+    pub fn field(&self, letter: char) -> Option<&Field> {
+        self.fields.iter().find(|field| field.letter == letter)
+    }
+
+    /// The raw bits of a field, or 0 if the pattern has no such field.
+    fn raw(&self, word: u16, letter: char) -> u16 {
+        self.field(letter).map_or(0, |field| word >> field.shift & field.max())
+    }
+
+    /// A field reduced to the range `machine` uses, as described on `Instruction`.
+    fn value(&self, word: u16, letter: char, machine: &Machine) -> u16 {
+        let raw = self.raw(word, letter);
+        match Kind::of(letter) {
+            Some(Kind::Register) => raw & (machine.registers - 1),
+            Some(Kind::Target) => raw & (machine.rom - 1),
+            Some(Kind::Data) if raw < IO_BASE as u16 => raw & (machine.ram - 1),
+            Some(Kind::Data) => raw & 0xff,
+            Some(Kind::Immediate | Kind::Argument) => raw & 0xff,
+            Some(Kind::Condition | Kind::Vector) => raw & 0xf,
+            None => raw,
+        }
+    }
+
+    pub fn decode(&self, word: u16, machine: &Machine) -> Instruction {
+        let value = |letter| self.value(word, letter, machine);
+        let (d, a, b, s, p) = (value('d'), value('a'), value('b'), value('s'), value('p'));
+
+        match self.micro_op {
+            MicroOp::Interrupt => Instruction::Int { vector: value('v'), argument: value('n') as u8 },
+            MicroOp::Add => Instruction::Add { dest: d, src_a: a, src_b: b },
+            MicroOp::Sub => Instruction::Sub { dest: d, src_a: a, src_b: b },
+            MicroOp::And => Instruction::And { dest: d, src_a: a, src_b: b },
+            MicroOp::Nor => Instruction::Nor { dest: d, src_a: a, src_b: b },
+            MicroOp::Xor => Instruction::Xor { dest: d, src_a: a, src_b: b },
+            MicroOp::ShiftRight => Instruction::Rsh { dest: d, src: a },
+            MicroOp::Compare => Instruction::Cmp { src_a: a, src_b: b, signed: false },
+            MicroOp::CompareSigned => Instruction::Cmp { src_a: a, src_b: b, signed: true },
+            MicroOp::LoadImmediate => Instruction::Imm { dest: d, value: value('i') as u8 },
+            MicroOp::Load => Instruction::Dml { dest: d, addr: value('m') as u8 },
+            MicroOp::Store => Instruction::Dms { src: s, addr: value('m') as u8 },
+            MicroOp::LoadIndirect => Instruction::Iml { dest: d, ptr: p },
+            MicroOp::StoreIndirect => Instruction::Ims { ptr: p, src: s },
+            MicroOp::Branch => Instruction::Brc { cond: value('c'), addr: value('t') },
+            MicroOp::BranchIndirect => Instruction::Ibr { cond: value('c'), ptr: p },
+            MicroOp::Jump => Instruction::Jmp { addr: value('t') },
+            MicroOp::Call => Instruction::Cal { addr: value('t') },
+            MicroOp::Return => Instruction::Ret,
+        }
+    }
+
+    /// Formats `word` as assembler source; see `Machine::format_with`.
+    pub fn format_with(&self, word: u16, machine: &Machine, target: impl Fn(u16) -> String) -> String {
+        let operands: Vec<String> = self.operands.iter()
+            .map(|operand| match operand {
+                Operand::Ignored => "0".to_string(),
+                Operand::Fields(letters) if letters.len() > 1 => {
+                    format!("0x{:01$x}", self.join(word, letters), self.operand_width(letters).div_ceil(4) as usize)
+                }
+                Operand::Fields(letters) => {
+                    let letter = letters[0];
+                    let value = self.value(word, letter, machine);
+                    match Kind::of(letter) {
+                        Some(Kind::Condition) => FLAG_NAMES[value as usize].to_string(),
+                        Some(Kind::Target) => target(value),
+                        Some(Kind::Data) if value >= IO_BASE as u16 => match self.micro_op {
+                            MicroOp::Store => format!("out{}", value - IO_BASE as u16),
+                            _ => format!("inp{}", value - IO_BASE as u16),
+                        },
+                        _ => value.to_string(),
+                    }
+                }
+            })
+            .collect();
+
+        match operands.is_empty() {
+            true => self.mnemonic.clone(),
+            false => format!("{} {}", self.mnemonic, operands.join(", ")),
+        }
+    }
+
+    /// Several fields read as one number, the first letter highest.
+    fn join(&self, word: u16, letters: &[char]) -> u16 {
+        letters.iter().fold(0, |acc, &letter| {
+            let width = self.field(letter).map_or(0, |field| field.width);
+            acc << width | self.raw(word, letter)
+        })
+    }
+
+    /// Places `value` into the fields of `letters`, the first letter highest.
+    pub fn place(&self, letters: &[char], mut value: u16) -> u16 {
+        let mut word = 0;
+        for field in letters.iter().rev().filter_map(|&letter| self.field(letter)) {
+            word |= (value & field.max()) << field.shift;
+            value >>= field.width;
+        }
+        word
+    }
+
+    /// The total width of an operand's fields.
+    pub fn operand_width(&self, letters: &[char]) -> u16 {
+        letters.iter().filter_map(|&letter| self.field(letter)).map(|field| field.width).sum()
+    }
+
+    /// The largest number an operand can hold.
+    pub fn operand_max(&self, letters: &[char]) -> u32 {
+        (1u32 << self.operand_width(letters)) - 1
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Isa {
+    pub name: String,
+    definitions: Vec<Definition>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IsaError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for IsaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line + 1, self.message)
+    }
+}
+
+fn error(line: usize, message: String) -> IsaError {
+    IsaError { line, message }
+}
+
+impl Default for Isa {
+    fn default() -> Self {
+        Isa::nano()
+    }
+}
+
+impl Isa {
+    pub fn nano() -> Isa {
+        Isa::nano_with(false, false)
+    }
+
+    /// The stock set with the call stack and signed compare extensions as
+    /// selected, named `nano`, `nano-stack`, `nano-cms` or `nano-stack-cms`.
+    pub fn nano_with(stack: bool, signed_compare: bool) -> Isa {
+        static ISAS: [OnceLock<Isa>; 4] = [OnceLock::new(), OnceLock::new(), OnceLock::new(), OnceLock::new()];
+        ISAS[stack as usize | (signed_compare as usize) << 1].get_or_init(|| {
+            let (stack_table, stack_name) = match stack {
+                true => (STACK, "-stack"),
+                false => ("", ""),
+            };
+            let (compare_table, compare_name) = match signed_compare {
+                true => (SIGNED_COMPARE, "-cms"),
+                false => ("", ""),
+            };
+            let mut isa: Isa = format!("{}{}{}", stack_table, compare_table, NANO).parse().expect("built-in ISA");
+            isa.name = format!("nano{}{}", stack_name, compare_name);
+            isa
+        }).clone()
+    }
+
+    pub fn definitions(&self) -> &[Definition] {
+        &self.definitions
+    }
+
+    /// The form that decodes `word`: the first whose pattern matches.
+    pub fn definition(&self, word: u16) -> &Definition {
+        self.definitions.iter().find(|definition| definition.matches(word))
+            .expect("every word matches some form")
+    }
+
+    /// Index of the form that decodes `word`.
+    pub fn index(&self, word: u16) -> usize {
+        self.definitions.iter().position(|definition| definition.matches(word))
+            .expect("every word matches some form")
+    }
+
+    /// The forms of `mnemonic`, in the order the assembler tries them.
+    pub fn forms<'a>(&'a self, mnemonic: &'a str) -> impl Iterator<Item = &'a Definition> + 'a {
+        self.definitions.iter().filter(move |definition| definition.mnemonic.eq_ignore_ascii_case(mnemonic))
+    }
+}
+
+fn parse_definition(line: usize, text: &str) -> Result<Definition, IsaError> {
+    let mut tokens = text.split_whitespace();
+    let mnemonic = tokens.next().unwrap().to_ascii_lowercase();
+
+    let mut pattern = String::new();
+    while pattern.len() < 16 {
+        match tokens.next() {
+            Some(group) if pattern.len() + group.len() <= 16 => pattern.push_str(group),
+            _ => return Err(error(line, format!("pattern of '{}' must have 16 bits", mnemonic))),
+        }
+    }
+
+    let (mut opcode, mut mask) = (0u16, 0u16);
+    let mut fields: Vec<Field> = Vec::new();
+    for (idx, c) in pattern.chars().enumerate() {
+        let bit = 15 - idx as u16;
+        match c {
+            '0' | '1' => {
+                mask |= 1 << bit;
+                opcode |= ((c == '1') as u16) << bit;
+            }
+            '-' => {}
+            _ if Kind::of(c).is_some() => match fields.iter().position(|field| field.letter == c) {
+                Some(idx) if idx == fields.len() - 1 && fields[idx].shift == bit + 1 => {
+                    fields[idx].shift = bit;
+                    fields[idx].width += 1;
+                }
+                Some(_) => return Err(error(line, format!("field '{}' of '{}' is not contiguous", c, mnemonic))),
+                None => fields.push(Field { letter: c, shift: bit, width: 1 }),
+            },
+            _ => return Err(error(line, format!("invalid pattern character '{}'", c))),
+        }
+    }
+
+    let micro_op: MicroOp = tokens.next()
+        .ok_or_else(|| error(line, format!("'{}' has no micro-op", mnemonic)))?
+        .parse().map_err(|message| error(line, message))?;
+    if let Some(field) = fields.iter().find(|field| !micro_op.fields().contains(field.letter)) {
+        return Err(error(line, format!("micro-op {} has no field '{}'", micro_op, field.letter)));
+    }
+
+    let flags = match tokens.next() {
+        Some("flags") if micro_op.has_flags() => true,
+        Some("flags") => return Err(error(line, format!("micro-op {} sets no flags", micro_op))),
+        Some("-") => false,
+        _ => return Err(error(line, format!("flags of '{}' must be 'flags' or '-'", mnemonic))),
+    };
+
+    let mut operands = Vec::new();
+    let mut used = String::new();
+    for token in tokens {
+        if token == "_" {
+            operands.push(Operand::Ignored);
+            continue;
+        }
+        for letter in token.chars() {
+            if fields.iter().all(|field| field.letter != letter) || used.contains(letter) {
+                return Err(error(line, format!("operand '{}' does not name unused fields of the pattern", token)));
+            }
+            used.push(letter);
+        }
+        operands.push(Operand::Fields(token.chars().collect()));
+    }
+    if let Some(field) = fields.iter().find(|field| !used.contains(field.letter)) {
+        return Err(error(line, format!("field '{}' of '{}' is not an operand", field.letter, mnemonic)));
+    }
+
+    Ok(Definition { mnemonic, opcode, mask, micro_op, flags, fields, operands })
+}
+
+/// Parses the table format in the module docs; `#` starts a comment.
+impl FromStr for Isa {
+    type Err = IsaError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut definitions = Vec::new();
+        let mut lines = Vec::new();
+        for (line, raw) in text.lines().enumerate() {
+            let entry = raw.split('#').next().unwrap_or("").trim();
+            if !entry.is_empty() {
+                definitions.push(parse_definition(line, entry)?);
+                lines.push(line);
+            }
+        }
+
+        let isa = Isa { name: "custom".to_string(), definitions };
+        let mut decoded = vec![0usize; isa.definitions.len()];
+        for word in 0..=u16::MAX {
+            match isa.definitions.iter().position(|definition| definition.matches(word)) {
+                Some(idx) => decoded[idx] += 1,
+                None => return Err(error(text.lines().count().saturating_sub(1), format!("word {:04x} matches no instruction", word))),
+            }
+        }
+        for (idx, definition) in isa.definitions.iter().enumerate() {
+            let respelled = isa.definitions[..idx].iter().any(|earlier| earlier.mnemonic == definition.mnemonic
+                && (earlier.opcode, earlier.mask, &earlier.fields) == (definition.opcode, definition.mask, &definition.fields));
+            if decoded[idx] == 0 && !respelled {
+                return Err(error(lines[idx], format!("'{}' overlaps earlier patterns and decodes no word", definition.mnemonic)));
+            }
+        }
+        Ok(isa)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::{assemble, AsmError};
+
+    /// The bitfield decoder the table replaced, with `cms` on bit 8 only when `signed_compare`.
+    fn reference(word: u16, machine: &Machine, signed_compare: bool) -> Instruction {
+        let (r, pc) = (machine.registers - 1, machine.rom - 1);
+        let data = |addr: u16| match addr >= IO_BASE as u16 {
+            true => addr as u8,
+            false => (addr & (machine.ram - 1)) as u8,
+        };
+        let a = word >> 8 & 0xf;
+        let b = word >> 4 & 0xf;
+        let c = word & 0xf;
+        let low = word & 0xff;
+
+        match word >> 12 {
+            0x0 => Instruction::Int { vector: a, argument: low as u8 },
+            0x1 => Instruction::Add { dest: a & r, src_a: b & r, src_b: c & r },
+            0x2 => Instruction::Sub { dest: a & r, src_a: b & r, src_b: c & r },
+            0x3 => Instruction::And { dest: a & r, src_a: b & r, src_b: c & r },
+            0x4 => Instruction::Nor { dest: a & r, src_a: b & r, src_b: c & r },
+            0x5 => Instruction::Xor { dest: a & r, src_a: b & r, src_b: c & r },
+            0x6 => Instruction::Rsh { dest: a & r, src: b & r },
+            0x7 => Instruction::Cmp { src_a: b & r, src_b: c & r, signed: signed_compare && a & 1 != 0 },
+            0x8 => Instruction::Imm { dest: a & r, value: low as u8 },
+            0x9 => Instruction::Dml { dest: a & r, addr: data(low) },
+            0xa => Instruction::Dms { src: a & r, addr: data(low) },
+            0xb => Instruction::Iml { dest: a & r, ptr: b & r },
+            0xc => Instruction::Ims { ptr: b & r, src: c & r },
+            0xd => Instruction::Brc { cond: a, addr: low & pc },
+            0xe => Instruction::Ibr { cond: a, ptr: c & r },
+            _ => Instruction::Jmp { addr: word & pc },
+        }
+    }
+
+    #[test]
+    fn nano_decodes_like_bitfield_decoder() {
+        for preset in ["nano", "wide16"] {
+            for signed_compare in [false, true] {
+                let machine = Machine { signed_compare, isa: Isa::nano_with(false, signed_compare), ..Machine::preset(preset).unwrap() };
+                for word in 0..=u16::MAX {
+                    assert_eq!(machine.decode(word), reference(word, &machine, signed_compare), "{} {} {:04x}", preset, machine.isa.name, word);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stack_extension_takes_vectors_14_and_15() {
+        let machine = Machine { stack: 4, isa: Isa::nano_with(true, false), ..Machine::default() };
+        for word in 0..=u16::MAX {
+            let expected = match word >> 8 {
+                0x0e => Instruction::Cal { addr: word & 0x3f },
+                0x0f => Instruction::Ret,
+                _ => reference(word, &machine, false),
+            };
+            assert_eq!(machine.decode(word), expected, "{:04x}", word);
+        }
+    }
+
+    #[test]
+    fn stack_vectors_do_not_assemble_as_int() {
+        let machine = Machine { stack: 4, isa: Isa::nano_with(true, false), ..Machine::default() };
+        let cases = [
+            ("int 14, 3", "word 0e03 decodes as 'cal 3' on this machine, not as 'int'"),
+            ("int 15", "word 0f00 decodes as 'ret' on this machine, not as 'int'"),
+            ("int 0xf00", "word 0f00 decodes as 'ret' on this machine, not as 'int'"),
+        ];
+        for (source, message) in cases {
+            assert_eq!(assemble(source, &machine), Err(AsmError { line: 0, message: message.to_string() }), "{}", source);
+            assert!(assemble(source, &Machine::default()).is_ok(), "{}", source);
+        }
+        assert_eq!(assemble("int 13, 3\ncal 5\nret", &machine), Ok(vec![0x0d03, 0x0e05, 0x0f00]));
+    }
+
+    #[test]
+    fn rejects_malformed_tables() {
+        let catch_all = "und ---- ---- ---- ---- interrupt -\n";
+        let cases = [
+            ("add 0001 dddd aaaa bbb add flags d a b", 0, "pattern of 'add' must have 16 bits"),
+            ("add 0001 dddd aaaa bbbbb add flags d a b", 0, "pattern of 'add' must have 16 bits"),
+            ("add 0001 dddd aaaa xxxx add flags d a x", 0, "invalid pattern character 'x'"),
+            ("add 0001 dddd aaaa iiii add flags d a i", 0, "micro-op add has no field 'i'"),
+            ("add 0001 dddd aaaa dddd add flags d a", 0, "field 'd' of 'add' is not contiguous"),
+            ("add 0001 dddd aaaa bbbb add flags d a", 0, "field 'b' of 'add' is not an operand"),
+            ("add 0001 dddd aaaa bbbb add flags d a c", 0, "operand 'c' does not name unused fields of the pattern"),
+            ("add 0001 dddd aaaa bbbb plus flags d a b", 0, "unknown micro-op 'plus'"),
+            ("jmp 1111 tttt tttt tttt jump flags t", 0, "micro-op jump sets no flags"),
+            ("# no catch-all\nadd 0001 dddd aaaa bbbb add flags d a b", 1, "word 0000 matches no instruction"),
+            ("cmp 0111 ---- aaaa bbbb compare flags a b\ncms 0111 ---1 aaaa bbbb compare-signed flags a b", 1,
+             "'cms' overlaps earlier patterns and decodes no word"),
+            ("int 0000 vvvv nnnn nnnn interrupt - v n\nint 0000 vvvv 0000 0000 interrupt - v", 1,
+             "'int' overlaps earlier patterns and decodes no word"),
+        ];
+        for (table, line, message) in cases {
+            let table = match table.starts_with('#') {
+                true => table.to_string(),
+                false => format!("{}\n{}", table, catch_all),
+            };
+            assert_eq!(table.parse::<Isa>(), Err(IsaError { line, message: message.to_string() }), "{}", table);
+        }
+    }
+
+    #[test]
+    fn accepts_another_spelling_of_a_pattern() {
+        let table = "int 0000 vvvv nnnn nnnn interrupt - v n\nint 0000 vvvv nnnn nnnn interrupt - vn\nund ---- ---- ---- ---- interrupt -\n";
+        let isa: Isa = table.parse().unwrap();
+        assert_eq!(isa.forms("int").count(), 2);
+        assert_eq!(isa.index(0x0105), 0);
+    }
+}
@@ -0,0 +1,35 @@
+//! Headless core of the AnPU Nano emulator. The terminal UI in `main.rs` is one
+//! consumer of `Cpu`; anything else that wants to run AnPU Nano programs can
+//! drive it the same way.
+
+pub mod alu;
+pub mod asm;
+pub mod batch;
+pub mod cpu;
+pub mod debug;
+pub mod disasm;
+pub mod history;
+pub mod instruction;
+pub mod interrupt;
+pub mod isa;
+pub mod log;
+pub mod machine;
+pub mod profile;
+pub mod state;
+pub mod timing;
+pub mod trace;
+
+pub use asm::{assemble, AsmError};
+pub use batch::{Expectation, Status};
+pub use cpu::{Cpu, LoadError, StackFault, Step, Write};
+pub use debug::{Break, Condition, Debugger, Location, Watchpoint};
+pub use disasm::disassemble;
+pub use history::History;
+pub use profile::Profile;
+pub use instruction::{decode, Instruction};
+pub use isa::Isa;
+pub use machine::Machine;
+pub use interrupt::{Handler, Interrupt, Vectors};
+pub use state::StateError;
+pub use timing::TickCosts;
+pub use trace::{TraceError, TraceWriter};
@@ -0,0 +1,235 @@
+//! Bounded, searchable instruction log for front ends.
+//!
+//! Instruction entries keep only the raw facts (PC, word, write, jump) and
+//! are disassembled for the log's machine when displayed, so a long log stays
+//! cheap to append to.
+
+use std::collections::VecDeque;
+
+use crate::{cpu::{Cpu, Step, Write}, instruction::Instruction, machine::Machine};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Entry {
+    Step { pc: u16, word: u16, write: Option<Write>, jump: Option<u16> },
+    Message(String),
+}
+
+impl Entry {
+    /// Describes an executed instruction; `cpu` is the state after `step`.
+    pub fn step(cpu: &mut Cpu, step: &Step) -> Self {
+        let jump = match cpu.instruction(step.pc) {
+            _ if step.fault.is_some() => None,
+            Instruction::Brc { .. } | Instruction::Ibr { .. } | Instruction::Jmp { .. } | Instruction::Cal { .. } | Instruction::Ret
+                if cpu.pc != (step.pc + 1) % cpu.machine().rom => Some(cpu.pc),
+            _ => None,
+        };
+        Entry::Step { pc: step.pc, word: step.word, write: step.write, jump }
+    }
+
+    /// `PC instruction =result` for writes and `PC instruction ->target` for
+    /// taken jumps (including calls, whose pushed address is not shown),
+    /// disassembled for `machine`.
+    pub fn text(&self, machine: &Machine) -> String {
+        match self {
+            Entry::Step { pc, word, write, jump } => {
+                let text = format!("{:02} {}", pc, machine.format(*word));
+                match (write, jump) {
+                    (Some(Write::Reg { new, .. } | Write::Ram { new, .. } | Write::Out { new, .. }), _) => format!("{} ={:02x}", text, new),
+                    (_, Some(target)) => format!("{} ->{:02}", text, target),
+                    (_, None) => text,
+                }
+            }
+            Entry::Message(text) => text.clone(),
+        }
+    }
+}
+
+/// The log keeps at most `capacity` entries, dropping the oldest. A search
+/// narrows the view to matching entries; scrolling moves the view up from
+/// the newest entry and stays anchored while new entries arrive.
+pub struct Log {
+    machine: Machine,
+    entries: VecDeque<Entry>,
+    /// Absolute index of `entries[0]`, counting every entry ever pushed.
+    first: usize,
+    capacity: usize,
+    search: Option<String>,
+    /// Absolute indices of the entries matching `search`.
+    matches: VecDeque<usize>,
+    scroll: usize,
+}
+
+impl Log {
+    /// An empty log whose entries are disassembled for `machine`.
+    pub fn new(capacity: usize, machine: &Machine) -> Self {
+        Log { machine: machine.clone(), entries: VecDeque::new(), first: 0, capacity: capacity.max(1), search: None, matches: VecDeque::new(), scroll: 0 }
+    }
+
+    fn is_match(&self, search: &str, entry: &Entry) -> bool {
+        entry.text(&self.machine).to_ascii_lowercase().contains(search)
+    }
+
+    pub fn push(&mut self, entry: Entry) {
+        let visible = match &self.search {
+            Some(search) => self.is_match(search, &entry),
+            None => true,
+        };
+        if visible {
+            if self.search.is_some() {
+                self.matches.push_back(self.first + self.entries.len());
+            }
+            if self.scroll > 0 {
+                self.scroll += 1;
+            }
+        }
+        self.entries.push_back(entry);
+
+        if self.entries.len() > self.capacity {
+            self.entries.pop_front();
+            self.first += 1;
+            if self.matches.front() == Some(&(self.first - 1)) {
+                self.matches.pop_front();
+            }
+        }
+        self.scroll = self.scroll.min(self.len().saturating_sub(1));
+    }
+
+    pub fn clear(&mut self) {
+        self.first += self.entries.len();
+        self.entries.clear();
+        self.matches.clear();
+        self.scroll = 0;
+    }
+
+    /// Number of entries in the current view.
+    pub fn len(&self) -> usize {
+        match self.search {
+            Some(_) => self.matches.len(),
+            None => self.entries.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Shows only entries containing `search` (case-insensitively), or everything for `None`.
+    pub fn set_search(&mut self, search: Option<String>) {
+        self.search = search.map(|search| search.to_ascii_lowercase()).filter(|search| !search.is_empty());
+        self.matches = match &self.search {
+            Some(search) => self.entries.iter().enumerate()
+                .filter(|(_, entry)| self.is_match(search, entry))
+                .map(|(idx, _)| self.first + idx)
+                .collect(),
+            None => VecDeque::new(),
+        };
+        self.scroll = 0;
+    }
+
+    pub fn search(&self) -> Option<&str> {
+        self.search.as_deref()
+    }
+
+    /// Scrolls towards older (positive) or newer (negative) entries.
+    pub fn scroll_by(&mut self, rows: isize) {
+        self.scroll = self.scroll.saturating_add_signed(rows).min(self.len().saturating_sub(1));
+    }
+
+    pub fn scroll_to_oldest(&mut self) {
+        self.scroll = self.len().saturating_sub(1);
+    }
+
+    pub fn scroll_to_newest(&mut self) {
+        self.scroll = 0;
+    }
+
+    /// Whether the newest entry is at the bottom of the view.
+    pub fn is_following(&self) -> bool {
+        self.scroll == 0
+    }
+
+    /// The last `rows` entries of the view at the current scroll position,
+    /// oldest first and formatted, together with the 1-based position of the bottom one.
+    pub fn window(&self, rows: usize) -> (Vec<String>, usize) {
+        let end = self.len() - self.scroll.min(self.len());
+        let start = end.saturating_sub(rows);
+        let entries = (start..end)
+            .map(|idx| match self.search {
+                Some(_) => &self.entries[self.matches[idx] - self.first],
+                None => &self.entries[idx],
+            })
+            .map(|entry| entry.text(&self.machine))
+            .collect();
+        (entries, end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push(log: &mut Log, texts: &[&str]) {
+        for text in texts {
+            log.push(Entry::Message(text.to_string()));
+        }
+    }
+
+    fn assert_window(log: &Log, rows: usize, texts: &[&str], position: usize) {
+        let texts = texts.iter().map(|text| text.to_string()).collect();
+        assert_eq!(log.window(rows), (texts, position));
+    }
+
+    #[test]
+    fn stays_anchored_while_full_buffer_evicts() {
+        let mut log = Log::new(4, &Machine::default());
+        push(&mut log, &["0", "1", "2", "3"]);
+        log.scroll_by(2);
+        assert_window(&log, 2, &["0", "1"], 2);
+
+        // The view keeps showing "1" while older entries are dropped below it.
+        push(&mut log, &["4"]);
+        assert_window(&log, 2, &["1"], 1);
+        assert!(!log.is_following());
+
+        // Once "1" is dropped too, the view stops at the oldest entry left.
+        push(&mut log, &["5", "6"]);
+        assert_eq!(log.len(), 4);
+        assert_window(&log, 2, &["3"], 1);
+
+        log.scroll_to_newest();
+        assert_window(&log, 2, &["5", "6"], 4);
+        push(&mut log, &["7"]);
+        assert_window(&log, 2, &["6", "7"], 4);
+    }
+
+    #[test]
+    fn search_follows_the_buffer_as_it_wraps() {
+        let mut log = Log::new(4, &Machine::default());
+        push(&mut log, &["hit 0", "miss 1", "hit 2"]);
+        log.set_search(Some("HIT".to_string()));
+        assert_window(&log, 5, &["hit 0", "hit 2"], 2);
+
+        push(&mut log, &["miss 3", "hit 4", "miss 5", "hit 6", "miss 7"]);
+        assert_window(&log, 5, &["hit 4", "hit 6"], 2);
+
+        // Scrolled up, a new match moves the view and a non-match does not.
+        log.scroll_by(1);
+        assert_window(&log, 1, &["hit 4"], 1);
+        push(&mut log, &["hit 8"]);
+        assert_window(&log, 1, &["hit 6"], 1);
+        push(&mut log, &["miss 9"]);
+        assert_window(&log, 1, &["hit 6"], 1);
+
+        // Dropping the anchored match leaves only newer ones in view.
+        push(&mut log, &["miss 10"]);
+        assert_eq!(log.len(), 1);
+        assert_window(&log, 5, &["hit 8"], 1);
+        assert!(log.is_following());
+
+        // A new search after wrapping indexes the entries still held.
+        log.set_search(Some("miss".to_string()));
+        assert_window(&log, 5, &["miss 7", "miss 9", "miss 10"], 3);
+        log.set_search(None);
+        assert_window(&log, 5, &["miss 7", "hit 8", "miss 9", "miss 10"], 4);
+    }
+}
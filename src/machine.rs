@@ -0,0 +1,196 @@
+//! Machine geometry: how much ROM and RAM a build has and how many registers.
+//!
+//! A machine is a built-in preset or a small TOML file:
+//!
+//! ```toml
+//! [machine]
+//! name = "wide"
+//! rom = 256        # words, up to 256 (the 8-bit `brc` target)
+//! ram = 128        # bytes, up to 128 (data addresses from `IO_BASE` are ports)
+//! registers = 16   # up to 16 (the 4-bit register fields)
+//! stack = 8        # return addresses, up to 16; 0 (the default) has no call stack
+//! signed_compare = true   # adds `cms`; false (the default) keeps stock `cmp`
+//! ```
+//!
+//! Every size must be a power of two, so decoding reduces operands with a mask
+//! just as the stock build ignores the high bits of its fields. The stack depth
+//! may be any number: a machine with a stack gets the call stack extension,
+//! `cal` and `ret` on interrupt vectors 14 and 15 (see `isa::STACK`), and
+//! `signed_compare` gives the `cmp` words with bit 8 set to `cms` (see
+//! `isa::SIGNED_COMPARE`). The instruction set is the stock one unless a table
+//! from `isa` replaces it.
+
+use std::{fmt, str::FromStr};
+
+use crate::{asm::parse_number, instruction::Instruction, isa::Isa};
+
+pub const MAX_ROM: u16 = 256;
+pub const MAX_RAM: u16 = 128;
+pub const MAX_REGISTERS: u16 = 16;
+pub const MAX_STACK: u16 = 16;
+
+/// Built-in machines: the stock AnPU Nano, and larger variants that use the
+/// full width of the instruction fields.
+pub const PRESETS: [(&str, u16, u16, u16); 3] = [
+    ("nano", 64, 32, 8),
+    ("wide", 256, 128, 8),
+    ("wide16", 256, 128, 16),
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Machine {
+    pub name: String,
+    pub rom: u16,
+    pub ram: u16,
+    pub registers: u16,
+    /// Depth of the hardware call stack; 0 leaves out the extension.
+    pub stack: u16,
+    /// Whether the signed compare extension is in.
+    pub signed_compare: bool,
+    pub isa: Isa,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MachineError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for MachineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line + 1, self.message)
+    }
+}
+
+fn error(line: usize, message: String) -> MachineError {
+    MachineError { line, message }
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Machine::preset("nano").unwrap()
+    }
+}
+
+impl Machine {
+    pub fn preset(name: &str) -> Option<Machine> {
+        PRESETS.iter()
+            .find(|(preset, ..)| preset.eq_ignore_ascii_case(name))
+            .map(|&(name, rom, ram, registers)| Machine { name: name.to_string(), rom, ram, registers, stack: 0, signed_compare: false, isa: Isa::nano() })
+    }
+
+    /// Checks the sizes against what the instruction encoding can address.
+    pub fn validate(&self) -> Result<(), String> {
+        check_size("rom", self.rom)?;
+        check_size("ram", self.ram)?;
+        check_size("registers", self.registers)?;
+        check_stack(self.stack)
+    }
+
+    /// Whether both machines have the same sizes, whatever they are called.
+    pub fn same_geometry(&self, other: &Machine) -> bool {
+        (self.rom, self.ram, self.registers, self.stack) == (other.rom, other.ram, other.registers, other.stack)
+    }
+
+    /// Number of bits in the program counter.
+    pub fn pc_bits(&self) -> u32 {
+        self.rom.trailing_zeros()
+    }
+
+    /// Decodes a 16-bit ROM word with this machine's instruction set and sizes.
+    pub fn decode(&self, word: u16) -> Instruction {
+        self.isa.definition(word).decode(word, self)
+    }
+
+    /// Formats a ROM word as assembler source.
+    pub fn format(&self, word: u16) -> String {
+        self.format_with(word, |addr| addr.to_string())
+    }
+
+    /// Formats a ROM word as assembler source, printing the jump target (if
+    /// any) with `target` so callers can substitute labels.
+    pub fn format_with(&self, word: u16, target: impl Fn(u16) -> String) -> String {
+        self.isa.definition(word).format_with(word, self, target)
+    }
+}
+
+pub(crate) fn check_size(what: &str, size: u16) -> Result<(), String> {
+    let (min, max) = match what {
+        "rom" => (2, MAX_ROM),
+        "ram" => (1, MAX_RAM),
+        _ => (2, MAX_REGISTERS),
+    };
+    match size.is_power_of_two() && (min..=max).contains(&size) {
+        true => Ok(()),
+        false => Err(format!("{} must be a power of two from {} to {}, not {}", what, min, max, size)),
+    }
+}
+
+pub(crate) fn check_stack(depth: u16) -> Result<(), String> {
+    match depth <= MAX_STACK {
+        true => Ok(()),
+        false => Err(format!("stack must be from 0 to {}, not {}", MAX_STACK, depth)),
+    }
+}
+
+/// `name (64 words ROM, 32 bytes RAM, 8 registers)`, with `, 8-deep stack` if it has one.
+impl fmt::Display for Machine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({} words ROM, {} bytes RAM, {} registers", self.name, self.rom, self.ram, self.registers)?;
+        match self.stack {
+            0 => f.write_str(")"),
+            depth => write!(f, ", {}-deep stack)", depth),
+        }
+    }
+}
+
+/// Parses the TOML subset shown in the module docs. Keys that are left out
+/// keep the stock AnPU Nano size, so the result is always valid.
+impl FromStr for Machine {
+    type Err = MachineError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut machine = Machine { name: "custom".to_string(), ..Machine::default() };
+
+        for (line, raw) in text.lines().enumerate() {
+            let entry = raw.split('#').next().unwrap_or("").trim();
+            if entry.is_empty() || entry == "[machine]" {
+                continue;
+            }
+            let (key, value) = entry.split_once('=').ok_or_else(|| error(line, format!("expected 'key = value', found '{}'", entry)))?;
+            let (key, value) = (key.trim(), value.trim());
+            let size = || {
+                let size = parse_number(value)
+                    .and_then(|value| u16::try_from(value).ok())
+                    .ok_or_else(|| error(line, format!("invalid size '{}'", value)))?;
+                check_size(key, size).map_err(|message| error(line, message))?;
+                Ok(size)
+            };
+            match key {
+                "name" => match value.strip_prefix('"').and_then(|value| value.strip_suffix('"')) {
+                    Some(name) if !name.contains(['"', '\\']) => machine.name = name.to_string(),
+                    _ => return Err(error(line, format!("name must be a quoted string, not '{}'", value))),
+                },
+                "rom" => machine.rom = size()?,
+                "ram" => machine.ram = size()?,
+                "registers" => machine.registers = size()?,
+                "stack" => {
+                    let depth = parse_number(value)
+                        .and_then(|value| u16::try_from(value).ok())
+                        .ok_or_else(|| error(line, format!("invalid size '{}'", value)))?;
+                    check_stack(depth).map_err(|message| error(line, message))?;
+                    machine.stack = depth;
+                }
+                "signed_compare" => match value {
+                    "true" => machine.signed_compare = true,
+                    "false" => machine.signed_compare = false,
+                    _ => return Err(error(line, format!("signed_compare must be true or false, not '{}'", value))),
+                },
+                _ => return Err(error(line, format!("unknown key '{}'", key))),
+            }
+        }
+
+        machine.isa = Isa::nano_with(machine.stack > 0, machine.signed_compare);
+        Ok(machine)
+    }
+}
@@ -1,8 +1,10 @@
 use std::{time::{Duration, Instant},
-          io::{stdout, Write},
-          fs,
+          io::{stdout, BufWriter, Write as _},
+          fs::{self, File},
           path::Path,
-          ffi::OsString};
+          env,
+          thread,
+          process};
 
 use crossterm::{QueueableCommand,
                 terminal::{self, SetSize, enable_raw_mode, disable_raw_mode, Clear, ClearType},
@@ -11,61 +13,180 @@ use crossterm::{QueueableCommand,
                 event::{read, poll, Event, KeyCode, KeyEventKind},
                 Result};
 
+use emulator::{batch, interrupt, log::{Entry, Log}, machine, timing, trace, Cpu, Debugger, Expectation, History, Interrupt, Isa, Machine, Profile,
+               StackFault, TickCosts, TraceWriter, Vectors, Watchpoint, Write};
+
 use crate::Mode::{Automatic, ManualStep, Setup};
 
-const WINDOW_SIZE: (u16, u16) = (65, 24);
+const WINDOW_SIZE: (u16, u16) = (65, 25);
+/// Columns the call stack panel adds to the right of the window.
+const STACK_WIDTH: u16 = 9;
+const HELP_WIDTH: usize = 61;
+const HISTORY_LENGTH: usize = 1 << 22;
+const LOG_LENGTH: usize = 1 << 20;
+const LOG_ROWS: usize = 7;
+const FRAME_TIME: Duration = Duration::from_micros(1_000_000 / 30);
+
+/// Clock rate of the stock in-game build, which spends the default 10 redstone
+/// ticks of `TickCosts` on every instruction.
+const REDSTONE_HZ: u16 = 1;
+/// Target frequencies selectable with `+`/`-`; 0 runs as fast as the host allows.
+const SPEED_PRESETS: [u16; 10] = [REDSTONE_HZ, 2, 5, 10, 30, 100, 1000, 10000, 50000, 0];
 
 const BG_COLOR: Color = Color::Black;
 const FIELD_COLOR: Color = Color::Black;
+const CURSOR_COLOR: Color = Color::DarkGrey;
+/// Heatmap backgrounds from rarely to most often executed.
+const HEAT_COLORS: [Color; 6] = [Color::DarkBlue, Color::Blue, Color::DarkCyan, Color::DarkGreen, Color::DarkYellow, Color::DarkMagenta];
+/// Entries per section of the exit profile report.
+const REPORT_LENGTH: usize = 10;
+/// Cells each panel shows at once; machines with more page through them.
+const ROM_PAGE: u16 = 64;
+const RAM_PAGE: u16 = 32;
+const REG_PAGE: u16 = 8;
+
+/// Cursor state of the INP panel editor.
+struct InpEditor {
+    port: u16,
+    bit: u16,
+    entry: String,
+}
+
+/// What the text typed on the help row is for.
+enum Prompt {
+    Watch,
+    Search,
+    /// A byte for the `input` interrupt waiting on register `reg`; `resume`
+    /// restarts `Automatic` mode once it is given.
+    Input { reg: u16, resume: bool },
+}
+
+/// Cells touched since a frame was drawn, one bit per cell of the largest machine.
+#[derive(Clone, Copy, Default)]
+struct Highlights {
+    rom: [u64; machine::MAX_ROM as usize / 64],
+    ram: u128,
+    reg: u16,
+    out: u8,
+    stack: u16,
+}
+
+impl Highlights {
+    fn mark_rom(&mut self, idx: u16) {
+        self.rom[idx as usize / 64] |= 1 << (idx % 64);
+    }
+
+    fn has_rom(&self, idx: u16) -> bool {
+        self.rom[idx as usize / 64] >> (idx % 64) & 1 != 0
+    }
+
+    fn mark_write(&mut self, write: Option<Write>) {
+        match write {
+            Some(Write::Reg { idx, .. }) => self.reg |= 1 << idx,
+            Some(Write::Ram { idx, .. }) => self.ram |= 1 << idx,
+            Some(Write::Out { idx, .. }) => self.out |= 1 << idx,
+            Some(Write::Stack { idx, .. }) => self.stack |= 1 << idx,
+            None => {}
+        }
+    }
+}
+
+/// ROM candidates offered by the `L` key, shown in place of the log.
+struct FilePicker {
+    entries: Vec<String>,
+    selected: usize,
+}
 
 enum Mode {
     Setup,
     ManualStep,
+    /// Running at a target frequency in Hz, or unthrottled for 0.
     Automatic(u16),
 }
 
-struct EmulatorState {
-    rom: [u32; 64],
-    ram: [u16; 32],
-    reg: [u16; 8],
-    inp: [u16; 8],
-    out: [u16; 8],
-    flg: [bool; 16],
-    pc: u16,
+/// Spaces instructions evenly at a target frequency. A pacer that falls more
+/// than a frame behind drops the backlog instead of bursting to catch up.
+struct Pacer {
+    next: Instant,
+}
 
-    mode: Mode,
-    log_buffer: [String; 7],
+impl Pacer {
+    fn new() -> Self {
+        Pacer { next: Instant::now() }
+    }
 
-    executed_instructions: usize,
+    /// Claims the next instruction slot if it is due.
+    fn ready(&mut self, hz: u16) -> bool {
+        let now = Instant::now();
+        if now < self.next {
+            return false;
+        }
+        if self.next + FRAME_TIME < now {
+            self.next = now;
+        }
+        self.next += Duration::from_secs(1) / hz as u32;
 
-    current_rom_read: Option<u16>,
-    current_ram_write: Option<u16>,
-    current_reg_write: Option<u16>,
+        true
+    }
+}
+
+struct EmulatorState {
+    cpu: Cpu,
+    rom_file_name: Option<String>,
+    ram_file_name: String,
+    state_file_name: Option<String>,
+    trace: Option<TraceWriter<BufWriter<File>>>,
+
+    mode: Mode,
+    speed: u16,
+    log_buffer: Log,
+
+    debugger: Debugger,
+    history: History,
+    profile: Profile,
+    heatmap: bool,
+    rom_cursor: u16,
+    /// Pages shown in the ROM, RAM and register panels.
+    rom_page: u16,
+    ram_page: u16,
+    reg_page: u16,
+    resuming: bool,
+    prompt: Option<(Prompt, String)>,
+    inp_editor: Option<InpEditor>,
+    picker: Option<FilePicker>,
+
+    /// What the last frame highlighted, and what has been touched since.
+    highlights: Highlights,
+    pending: Highlights,
+    current_break: Option<u16>,
 }
 
-#[allow(arithmetic_overflow)]
 impl EmulatorState {
     fn full_reset(&mut self) -> Result<()> {
-        self.rom = [0; 64];
-        self.program_reset()?;
+        let executed_instructions = self.cpu.executed_instructions;
+        self.cpu.full_reset();
+        self.rom_file_name = None;
+        self.debugger.clear();
+        self.reset_view(executed_instructions)?;
 
         Ok(())
     }
 
     fn program_reset(&mut self) -> Result<()> {
-        self.ram = [0; 32];
-        self.reg = [0; 8];
-        self.inp = [0; 8];
-        self.out = [0; 8];
-        self.flg = [false; 16];
-        self.flg[15] = true;
-        self.pc = 0;
+        let executed_instructions = self.cpu.executed_instructions;
+        self.cpu.program_reset();
+        self.reset_view(executed_instructions)?;
 
+        Ok(())
+    }
+
+    fn reset_view(&mut self, executed_instructions: usize) -> Result<()> {
         self.mode = Setup;
-        self.log_buffer = Default::default();
+        self.log_buffer.clear();
+        self.history.clear();
+        self.profile.clear();
 
-        self.push_log(format!("Ex. instr: {}", self.executed_instructions))?;
-        self.executed_instructions = 0;
+        self.push_log(format!("Ex. instr: {}", executed_instructions))?;
 
         self.reset_last_mods()?;
         self.draw_contents()?;
@@ -74,56 +195,195 @@ impl EmulatorState {
     }
 
     fn reset_last_mods(&mut self) -> Result<()> {
+        let shown = std::mem::take(&mut self.highlights);
+        self.pending = Highlights::default();
+
+        if let Some(i) = self.current_break.take() {
+            self.draw_rom_cell(i)?;
+        }
+        self.draw_highlighted(shown)?;
+
+        Ok(())
+    }
+
+    /// Redraws every cell marked in `cells`.
+    fn draw_highlighted(&self, cells: Highlights) -> Result<()> {
+        let machine = self.cpu.machine();
+        for idx in (0..machine.rom).filter(|&idx| cells.has_rom(idx)) {
+            self.draw_rom_cell(idx)?;
+        }
+        for idx in (0..machine.ram).filter(|idx| cells.ram >> idx & 1 != 0) {
+            self.draw_ram_cell(idx)?;
+        }
+        for idx in (0..machine.registers).filter(|idx| cells.reg >> idx & 1 != 0) {
+            self.draw_reg_cell(idx)?;
+        }
+        for idx in (0..8).filter(|idx| cells.out >> idx & 1 != 0) {
+            self.draw_out_cell(idx)?;
+        }
+
+        Ok(())
+    }
+
+    /// Cells off the current page are left alone.
+    fn draw_rom_cell(&self, idx: u16) -> Result<()> {
         let mut stdout = stdout();
 
-        if let Some(i) = self.current_rom_read {
-            let i = i % 64;
-            let value = self.rom[i as usize] % 65536;
-            let hex = &format!("{value:x}");
-            stdout.queue(MoveTo((5 * (i % 8) + 6) as u16, (i / 8 + 3) as u16))?;
-            stdout.queue(PrintStyledContent(format!("{hex:0>0$}", 4).white()))?;
+        let idx = idx % self.cpu.machine().rom;
+        if idx / ROM_PAGE != self.rom_page {
+            return Ok(());
         }
+        let color = if self.highlights.has_rom(idx) {
+            Color::Green
+        } else if self.debugger.is_breakpoint(idx) {
+            Color::Red
+        } else {
+            Color::White
+        };
+        let background = if self.current_break == Some(idx) {
+            Color::DarkRed
+        } else if self.rom_cursor == idx {
+            CURSOR_COLOR
+        } else if self.heatmap {
+            heat_color(self.profile.hits(idx), self.profile.max_hits())
+        } else {
+            FIELD_COLOR
+        };
+
+        let value = self.cpu.rom[idx as usize];
+        let hex = &format!("{value:x}");
+        let cell = idx % ROM_PAGE;
+        stdout.queue(MoveTo(5 * (cell % 8) + 6, cell / 8 + 3))?;
+        stdout.queue(PrintStyledContent(format!("{hex:0>0$}", 4).with(color).on(background)))?;
+
+        Ok(())
+    }
+
+    fn draw_ram_cell(&self, idx: u16) -> Result<()> {
+        let mut stdout = stdout();
 
-        if let Some(i) = self.current_ram_write {
-            let i = i % 32;
-            let value = self.ram[i as usize] % 256;
-            let hex = &format!("{value:x}");
-            stdout.queue(MoveTo((3 * (i % 4) + 52) as u16, (i / 4 + 3) as u16))?;
-            stdout.queue(PrintStyledContent(format!("{hex:0>0$}", 2).white()))?;
+        let idx = idx % self.cpu.machine().ram;
+        if idx / RAM_PAGE != self.ram_page {
+            return Ok(());
         }
+        let color = match self.highlights.ram >> idx & 1 != 0 {
+            true => Color::Green,
+            false => Color::White,
+        };
+        let value = self.cpu.ram[idx as usize];
+        let hex = &format!("{value:x}");
+        let cell = idx % RAM_PAGE;
+        stdout.queue(MoveTo(3 * (cell % 4) + 52, cell / 4 + 3))?;
+        stdout.queue(PrintStyledContent(format!("{hex:0>0$}", 2).with(color)))?;
 
-        if let Some(i) = self.current_reg_write {
-            let i = i % 8;
-            let val = self.reg[i as usize];
-            let hex = &format!("{val:x}");
-            stdout.queue(MoveTo(6, 13 + i)).unwrap();
-            stdout.queue(PrintStyledContent(format!("{hex:0>0$}", 2).white()))?;
+        Ok(())
+    }
+
+    fn draw_reg_cell(&self, idx: u16) -> Result<()> {
+        let mut stdout = stdout();
+
+        let idx = idx % self.cpu.machine().registers;
+        if idx / REG_PAGE != self.reg_page {
+            return Ok(());
         }
+        let color = match self.highlights.reg >> idx & 1 != 0 {
+            true => Color::Green,
+            false => Color::White,
+        };
+        let val = self.cpu.reg[idx as usize];
+        let hex = &format!("{val:x}");
+        stdout.queue(MoveTo(6, 13 + idx % REG_PAGE))?;
+        stdout.queue(PrintStyledContent(format!("{hex:0>0$}", 2).with(color)))?;
 
-        self.current_rom_read = None;
-        self.current_ram_write = None;
-        self.current_reg_write = None;
+        Ok(())
+    }
+
+    fn draw_inp_cell(&self, idx: u16) -> Result<()> {
+        let mut stdout = stdout();
+
+        let idx = idx % 8;
+        let background = match &self.inp_editor {
+            Some(editor) if editor.port == idx => CURSOR_COLOR,
+            _ => FIELD_COLOR,
+        };
+        let val = self.cpu.inp[idx as usize];
+        let hex = &format!("{val:x}");
+        stdout.queue(MoveTo(15, 13 + idx))?;
+        stdout.queue(PrintStyledContent(format!("{hex:0>0$}", 2).white().on(background)))?;
 
         Ok(())
     }
 
-    fn draw_log(&mut self) -> Result<()> {
+    fn draw_out_cell(&self, idx: u16) -> Result<()> {
         let mut stdout = stdout();
 
-        for i in 0..6 {
-            stdout.queue(MoveTo(41, 14 + i))?;
-            if self.log_buffer[i as usize].len() < 22 {
-                stdout.queue(PrintStyledContent(self.log_buffer[i as usize].clone().white()))?;
-            } else {
-                stdout.queue(PrintStyledContent(self.log_buffer[i as usize].clone()[..22].white()))?;
-            }
+        let idx = idx % 8;
+        let color = match self.highlights.out >> idx & 1 != 0 {
+            true => Color::Green,
+            false => Color::White,
+        };
+        let val = self.cpu.out[idx as usize];
+        let hex = &format!("{val:x}");
+        stdout.queue(MoveTo(24, 13 + idx))?;
+        stdout.queue(PrintStyledContent(format!("{hex:0>0$}", 2).with(color)))?;
+
+        Ok(())
+    }
+
+    /// Call stack slots, bottom first; slots above the stack pointer show `--`.
+    fn draw_stack(&self) -> Result<()> {
+        let mut stdout = stdout();
+
+        let depth = self.cpu.machine().stack;
+        let bits = stack_index_bits(depth);
+        for idx in 0..depth {
+            let text = match idx < self.cpu.sp {
+                true => format!("{:02x}", self.cpu.stack[idx as usize]),
+                false => "--".to_string(),
+            };
+            let color = match self.highlights.stack >> idx & 1 != 0 {
+                true => Color::Green,
+                false => Color::White,
+            };
+            stdout.queue(MoveTo(67 + bits, 3 + idx))?;
+            stdout.queue(PrintStyledContent(text.with(color)))?;
         }
-        stdout.queue(MoveTo(41, 20))?;
-        if self.log_buffer[6].len() < 22 {
-            stdout.queue(PrintStyledContent(self.log_buffer[6].clone().green()))?;
-        } else {
-            stdout.queue(PrintStyledContent(self.log_buffer[6].clone()[..22].green()))?;
+
+        Ok(())
+    }
+
+    /// The terminal size the layout needs: wider for machines with a call stack.
+    fn window_size(&self) -> (u16, u16) {
+        match self.cpu.machine().stack {
+            0 => WINDOW_SIZE,
+            _ => (WINDOW_SIZE.0 + STACK_WIDTH, WINDOW_SIZE.1),
         }
+    }
+
+    fn draw_log(&mut self) -> Result<()> {
+        let mut stdout = stdout();
+
+        let (entries, position) = self.log_buffer.window(LOG_ROWS);
+        let blank = LOG_ROWS - entries.len();
+        for row in 0..LOG_ROWS {
+            let text = match row.checked_sub(blank) {
+                Some(idx) => format!("{: <22.22}", entries[idx]),
+                None => " ".repeat(22),
+            };
+            stdout.queue(MoveTo(41, 14 + row as u16))?;
+            match row == LOG_ROWS - 1 && self.log_buffer.is_following() {
+                true => stdout.queue(PrintStyledContent(text.green()))?,
+                false => stdout.queue(PrintStyledContent(text.white()))?,
+            };
+        }
+
+        let status = match (self.log_buffer.search(), self.log_buffer.is_following()) {
+            (None, true) => " PgUp/PgDn  / search ".to_string(),
+            (None, false) => format!(" {}/{} ", position, self.log_buffer.len()),
+            (Some(search), _) => format!(" /{} {}/{} ", search, position, self.log_buffer.len()),
+        };
+        stdout.queue(MoveTo(40, 13))?;
+        stdout.queue(PrintStyledContent(format!("═{:═<23.23}", status).white()))?;
 
         Ok(())
     }
@@ -136,7 +396,7 @@ impl EmulatorState {
                 0..=7 => 0,
                 _ => 5
             } + 32, idx % 8 + 13))?;
-            let value = match self.flg[idx as usize] {
+            let value = match self.cpu.flg[idx as usize] {
                 true => "T",
                 false => "F"
             };
@@ -146,153 +406,107 @@ impl EmulatorState {
         Ok(())
     }
 
+    /// Appends to the log without drawing it; the next frame picks it up.
+    fn log(&mut self, new_entry: String) {
+        self.log_buffer.push(Entry::Message(new_entry));
+    }
+
     fn push_log(&mut self, new_entry: String) -> Result<()> {
-        for i in 1..7 {
-            self.log_buffer[i - 1] = self.log_buffer[i].clone();
-        }
-        self.log_buffer[6] = format!("{: <22}", new_entry);
+        self.log(new_entry);
 
         self.draw_log()?;
         Ok(())
     }
 
     fn draw_contents(&mut self) -> Result<()> {
-        let mut stdout = stdout();
-
-        for idx in 0..64 {
-            let value = self.rom[idx as usize] % 65536;
-            let hex = &format!("{value:x}");
-            stdout.queue(MoveTo((5 * (idx % 8) + 6) as u16, (idx / 8 + 3) as u16))?;
-            stdout.queue(PrintStyledContent(format!("{hex:0>0$}", 4).white()))?;
+        let machine = self.cpu.machine();
+        for idx in 0..machine.rom {
+            self.draw_rom_cell(idx)?;
         }
-        for idx in 0..32 {
-            let value = self.ram[idx as usize] % 256;
-            let hex = &format!("{value:x}");
-            stdout.queue(MoveTo((3 * (idx % 4) + 52) as u16, (idx / 4 + 3) as u16))?;
-            stdout.queue(PrintStyledContent(format!("{hex:0>0$}", 2).white()))?;
+        for idx in 0..machine.ram {
+            self.draw_ram_cell(idx)?;
         }
+        for idx in 0..machine.registers {
+            self.draw_reg_cell(idx)?;
+        }
+        self.draw_rom_status()?;
+        self.draw_page_status()?;
         for idx in 0..8 {
-            let val = self.reg[idx as usize];
-            let hex = &format!("{val:x}");
-            stdout.queue(MoveTo(6, 13 + idx))?;
-            stdout.queue(PrintStyledContent(format!("{hex:0>0$}", 2).white()))?;
-            let val = self.inp[idx as usize];
-            let hex = &format!("{val:x}");
-            stdout.queue(MoveTo(15, 13 + idx))?;
-            stdout.queue(PrintStyledContent(format!("{hex:0>0$}", 2).white()))?;
-            let val = self.out[idx as usize];
-            let hex = &format!("{val:x}");
-            stdout.queue(MoveTo(24, 13 + idx))?;
-            stdout.queue(PrintStyledContent(format!("{hex:0>0$}", 2).white()))?;
-        }
-
-        if let Some(i) = self.current_rom_read {
-            let i = i % 64;
-            let value = self.rom[i as usize] % 65536;
-            let hex = &format!("{value:x}");
-            stdout.queue(MoveTo((5 * (i % 8) + 6) as u16, (i / 8 + 3) as u16))?;
-            stdout.queue(PrintStyledContent(format!("{hex:0>0$}", 4).green()))?;
-        }
-
-        if let Some(i) = self.current_ram_write {
-            let i = i % 32;
-            let value = self.ram[i as usize] % 256;
-            let hex = &format!("{value:x}");
-            stdout.queue(MoveTo((3 * (i % 4) + 52) as u16, (i / 4 + 3) as u16))?;
-            stdout.queue(PrintStyledContent(format!("{hex:0>0$}", 2).green()))?;
-        }
-
-        if let Some(i) = self.current_reg_write {
-            let i = i % 8;
-            let val = self.reg[i as usize];
-            let hex = &format!("{val:x}");
-            stdout.queue(MoveTo(6, 13 + i))?;
-            stdout.queue(PrintStyledContent(format!("{hex:0>0$}", 2).green()))?;
+            self.draw_inp_cell(idx)?;
+            self.draw_out_cell(idx)?;
         }
 
         self.draw_pc()?;
         self.draw_flags()?;
+        self.draw_stack()?;
+        self.draw_game_time()?;
 
         self.draw_log()?;
 
         Ok(())
     }
 
-    fn write_to_rom(&mut self, idx: u16, val: u32) -> Result<()> {
+    /// Page and heatmap state in the top border of the ROM panel.
+    fn draw_rom_status(&self) -> Result<()> {
         let mut stdout = stdout();
 
-        let idx = idx % 64;
-        self.rom[idx as usize] = val % 65536;
-        let value = val % 65536;
-        let hex = &format!("{value:x}");
-        stdout.queue(MoveTo((5 * (idx % 8) + 6) as u16, (idx / 8 + 3) as u16))?;
-        stdout.queue(PrintStyledContent(format!("{hex:0>0$}", 4).white()))?;
+        let heatmap = match self.heatmap {
+            true => format!(" HEATMAP  max {} hits ", self.profile.max_hits()),
+            false => " H heatmap ".to_string(),
+        };
+        let status = match page_label(self.rom_page, ROM_PAGE, self.cpu.machine().rom) {
+            Some(page) => format!("{}═{}", page, heatmap),
+            None => heatmap,
+        };
+        stdout.queue(MoveTo(1, 1))?;
+        stdout.queue(PrintStyledContent(format!("═{:═<44.44}", status).white()))?;
 
         Ok(())
     }
 
-    fn read_from_rom(&mut self, idx: u16) -> u32 {
+    /// Pages of the RAM and register panels, in their top borders. Tab and
+    /// Shift+Tab turn them; a machine that fits its panels shows plain borders.
+    fn draw_page_status(&self) -> Result<()> {
         let mut stdout = stdout();
 
-        if let Some(i) = self.current_rom_read {
-            let i = i % 64;
-            let value = self.rom[i as usize] % 65536;
-            let hex = &format!("{value:x}");
-            stdout.queue(MoveTo((5 * (i % 8) + 6) as u16, (i / 8 + 3) as u16)).unwrap();
-            stdout.queue(PrintStyledContent(format!("{hex:0>0$}", 4).white())).unwrap();
-        }
-
-        let idx = idx % 64;
-        let val = self.rom[idx as usize] % 65536;
-        let hex = &format!("{val:x}");
-        stdout.queue(MoveTo((5 * (idx % 8) + 6) as u16, (idx / 8 + 3) as u16)).unwrap();
-        stdout.queue(PrintStyledContent(format!("{hex:0>0$}", 4).green())).unwrap();
+        let machine = self.cpu.machine();
+        let ram = page_label(self.ram_page, RAM_PAGE, machine.ram).map(|page| format!(" Tab{}", page));
+        let reg = page_label(self.reg_page, REG_PAGE, machine.registers);
+        stdout.queue(MoveTo(47, 1))?;
+        stdout.queue(PrintStyledContent(format!("{:═<17.17}", ram.unwrap_or_default()).white()))?;
+        stdout.queue(MoveTo(1, 11))?;
+        stdout.queue(PrintStyledContent(format!("{:═<8.8}", reg.unwrap_or_default()).white()))?;
 
-        self.current_rom_read = Some(idx);
-        return self.rom[idx as usize];
+        Ok(())
     }
 
-    fn write_to_ram(&mut self, idx: u16, val: u16) -> Result<()> {
-        let mut stdout = stdout();
-
-        if let Some(i) = self.current_ram_write {
-            let i = i % 32;
-            let value = self.ram[i as usize] % 256;
-            let hex = &format!("{value:x}");
-            stdout.queue(MoveTo((3 * (i % 4) + 52) as u16, (i / 4 + 3) as u16))?;
-            stdout.queue(PrintStyledContent(format!("{hex:0>0$}", 2).white()))?;
+    /// Shows the ROM page holding `idx`, redrawing the panel if it changes.
+    fn show_rom_page(&mut self, idx: u16) -> Result<()> {
+        let page = idx / ROM_PAGE;
+        if page != self.rom_page {
+            self.rom_page = page;
+            for idx in 0..self.cpu.machine().rom {
+                self.draw_rom_cell(idx)?;
+            }
+            self.draw_rom_status()?;
         }
 
-        let idx = idx % 32;
-        self.ram[idx as usize] = val % 256;
-        let val = val % 256;
-        let hex = &format!("{val:x}");
-        stdout.queue(MoveTo((3 * (idx % 4) + 52) as u16, (idx / 4 + 3) as u16))?;
-        stdout.queue(PrintStyledContent(format!("{hex:0>0$}", 2).green()))?;
-
-        self.current_ram_write = Some(idx);
-
         Ok(())
     }
 
-    fn write_to_regs(&mut self, idx: u16, val: u16) -> Result<()> {
+    /// Simulated in-game time and clock rate, from the tick cost model.
+    fn draw_game_time(&self) -> Result<()> {
         let mut stdout = stdout();
 
-        if let Some(i) = self.current_reg_write {
-            let i = i % 8;
-            let val = self.reg[i as usize];
-            let hex = &format!("{val:x}");
-            stdout.queue(MoveTo(6, 13 + i))?;
-            stdout.queue(PrintStyledContent(format!("{hex:0>0$}", 2).white()))?;
-        }
-
-        let idx = idx % 8;
-        self.reg[idx as usize] = val % 256;
-        let hex = &format!("{val:x}");
-        stdout.queue(MoveTo(6, 13 + idx))?;
-        stdout.queue(PrintStyledContent(format!("{hex:0>0$}", 2).green()))?;
-
-        self.current_reg_write = Some(idx);
+        let ticks = self.cpu.elapsed_ticks;
+        let text = format!("game {:.1}s @ {:.2}Hz", timing::seconds(ticks), timing::frequency(self.cpu.executed_instructions, ticks));
+        stdout.queue(MoveTo(21, 0))?;
+        stdout.queue(SetBackgroundColor(Color::Magenta))?;
+        stdout.queue(SetAttribute(Attribute::Bold))?;
+        stdout.queue(SetAttribute(Attribute::Underlined))?;
+        stdout.queue(PrintStyledContent(format!("{: <29.29}", text).white()))?;
+        stdout.queue(SetBackgroundColor(BG_COLOR))?;
+        stdout.queue(SetAttribute(Attribute::Reset))?;
 
         Ok(())
     }
@@ -306,9 +520,14 @@ impl EmulatorState {
         stdout.queue(MoveTo(41, 12))?;
         stdout.queue(PrintStyledContent("PC".magenta()))?;
         stdout.queue(SetAttribute(Attribute::Reset))?;
-        let pc = self.pc % 64;
-        let bin = format!("{pc:b}");
-        stdout.queue(PrintStyledContent(format!(" {bin:0>0$} ", 6).white()))?;
+        // Six binary digits fit the stock PC; wider ones are shown in hex.
+        let machine = self.cpu.machine();
+        let pc = self.cpu.pc % machine.rom;
+        let text = match machine.pc_bits() <= 6 {
+            true => format!("{:0>1$b}", pc, machine.pc_bits() as usize),
+            false => format!("0x{:02x}", pc),
+        };
+        stdout.queue(PrintStyledContent(format!(" {text: <6} ").white()))?;
         stdout.queue(PrintStyledContent("MODE: ".cyan()))?;
         self.draw_mode()?;
 
@@ -322,7 +541,7 @@ impl EmulatorState {
         match self.mode {
             Setup => stdout.queue(PrintStyledContent("HALTED".red()))?,
             ManualStep => stdout.queue(PrintStyledContent("MANUAL".yellow()))?,
-            Automatic(_) => stdout.queue(PrintStyledContent("SWOOSH".green()))?// stdout.queue(PrintStyledContent(format!("{speed:->0$}", 6).green()))?
+            Automatic(speed) => stdout.queue(PrintStyledContent(format!("{: <6}", speed_label(speed)).green()))?,
         };
 
         Ok(())
@@ -331,32 +550,45 @@ impl EmulatorState {
     fn draw_help(&mut self) -> Result<()> {
         let mut stdout = stdout();
 
-        match self.mode {
-            Setup => {
-                stdout.queue(MoveTo(2, 22))?;
-                stdout.queue(PrintStyledContent("L".cyan()))?;
-                stdout.queue(PrintStyledContent(" - Load next program ".white()))?;
-                stdout.queue(PrintStyledContent("C".cyan()))?;
-                stdout.queue(PrintStyledContent(" - clear ".white()))?;
-                stdout.queue(PrintStyledContent("R".cyan()))?;
-                stdout.queue(PrintStyledContent(" - run ".white()))?;
-                stdout.queue(PrintStyledContent("S".cyan()))?;
-                stdout.queue(PrintStyledContent(" - step ".white()))?;
-                stdout.queue(PrintStyledContent("Q".cyan()))?;
-                stdout.queue(PrintStyledContent(" - quit     ".white()))?;
-            }
-            ManualStep | Automatic(_) => {
-                stdout.queue(MoveTo(2, 22))?;
-                stdout.queue(PrintStyledContent(" ".cyan()))?;
-                stdout.queue(PrintStyledContent("                     ".white()))?;
-                stdout.queue(PrintStyledContent("C".cyan()))?;
-                stdout.queue(PrintStyledContent(" - clear ".white()))?;
-                stdout.queue(PrintStyledContent(" ".cyan()))?;
-                stdout.queue(PrintStyledContent("       ".white()))?;
-                stdout.queue(PrintStyledContent("S".cyan()))?;
-                stdout.queue(PrintStyledContent(" - step ".white()))?;
-                stdout.queue(PrintStyledContent("             ".white()))?;
+        let program: &[(&str, &str)] = match self.mode {
+            Setup => &[("L", "load"), ("C", "clear"), ("R", "run"), ("S", "step"), ("Z", "back"), ("D", "listing"), ("Q", "quit")],
+            ManualStep => &[("C", "clear"), ("R", "run"), ("S", "step"), ("Z", "back"), ("+/-", "speed"), ("F5/F9", "state")],
+            Automatic(_) => &[("C", "clear"), ("S", "stop"), ("+/-", "speed"), ("F5/F9", "state")],
+        };
+        let debug: &[(&str, &str)] = &[("Arrows", "select"), ("B", "breakpoint"), ("W", "watch"), ("X", "unwatch"), ("I", "inputs")];
+
+        draw_help_row(22, program)?;
+
+        if self.picker.is_some() {
+            draw_help_row(23, &[("Up/Down", "select"), ("Enter", "load"), ("Esc", "cancel")])?;
+        } else if let Some((prompt, text)) = &self.prompt {
+            let label = match prompt {
+                Prompt::Watch => "Watch: ".to_string(),
+                Prompt::Search => "Search: ".to_string(),
+                Prompt::Input { reg, .. } => format!("Input r{} (hex): ", reg),
+            };
+            stdout.queue(MoveTo(2, 23))?;
+            stdout.queue(PrintStyledContent(label.as_str().cyan()))?;
+            stdout.queue(PrintStyledContent(format!("{: <1$}", format!("{}_", text), HELP_WIDTH - label.len()).white()))?;
+        } else if let Some(editor) = &self.inp_editor {
+            let value = self.cpu.inp[editor.port as usize];
+            stdout.queue(MoveTo(2, 23))?;
+            stdout.queue(PrintStyledContent(format!("INP {:03b} ", editor.port).cyan()))?;
+            for bit in (0..8).rev() {
+                let digit = format!("{}", value >> bit & 1).white();
+                match bit == editor.bit {
+                    true => stdout.queue(PrintStyledContent(digit.on(CURSOR_COLOR)))?,
+                    false => stdout.queue(PrintStyledContent(digit))?,
+                };
             }
+            stdout.queue(PrintStyledContent(format!("  = {: <10}", format!("{}_", editor.entry)).white()))?;
+            stdout.queue(PrintStyledContent("Space".cyan()))?;
+            stdout.queue(PrintStyledContent(" toggle  ".white()))?;
+            stdout.queue(PrintStyledContent("Esc".cyan()))?;
+            stdout.queue(PrintStyledContent(" done".white()))?;
+            stdout.queue(Print(" ".repeat(HELP_WIDTH - 52)))?;
+        } else {
+            draw_help_row(23, debug)?;
         }
 
         Ok(())
@@ -367,44 +599,40 @@ impl EmulatorState {
 
         stdout.queue(SetBackgroundColor(BG_COLOR))?;
 
-        for i in 0..WINDOW_SIZE.0 {
-            for j in 0..WINDOW_SIZE.1 {
+        let (width, height) = self.window_size();
+        for i in 0..width {
+            for j in 0..height {
                 stdout.queue(MoveTo(i, j))?;
                 stdout.queue(Print(" "))?;
             }
         }
 
-        if let Some(i) = self.current_rom_read {
-            let i = i % 64;
-            let value = self.rom[i as usize] % 65536;
-            let hex = &format!("{value:x}");
-            stdout.queue(MoveTo((5 * (i % 8) + 6) as u16, (i / 8 + 3) as u16)).unwrap();
-            stdout.queue(PrintStyledContent(format!("{hex:0>0$}", 4).white())).unwrap();
-        }
-
-        if let Some(i) = self.current_ram_write {
-            let i = i % 32;
-            let value = self.ram[i as usize] % 256;
-            let hex = &format!("{value:x}");
-            stdout.queue(MoveTo(3 * (i % 4) + 52, (i / 4 + 3) as u16)).unwrap();
-            stdout.queue(PrintStyledContent(format!("{hex:0>0$}", 2).white())).unwrap();
-        }
-
-        if let Some(i) = self.current_reg_write {
-            let i = i % 8;
-            let val = self.reg[i as usize];
-            stdout.queue(MoveTo(6, 13 + i)).unwrap();
-            let hex = &format!("{val:x}");
-            stdout.queue(PrintStyledContent(format!("{hex:0>0$}", 2).white())).unwrap();
-        }
 
         stdout.queue(MoveTo(0, 0))?;
         stdout.queue(SetBackgroundColor(Color::Magenta))?;
         stdout.queue(SetAttribute(Attribute::Bold))?;
         stdout.queue(SetAttribute(Attribute::Underlined))?;
-        stdout.queue(PrintStyledContent(" AnPU Nano emulator                                              ".white()))?;
+        stdout.queue(PrintStyledContent(format!("{: <1$}", " AnPU Nano emulator", width as usize).white()))?;
         stdout.queue(SetAttribute(Attribute::Reset))?;
 
+        // Drawn first so the main panels' borders win where they meet it.
+        let depth = self.cpu.machine().stack;
+        if depth > 0 {
+            draw_box((64, 1), (STACK_WIDTH + 1, 24), "".to_string())?;
+            stdout.queue(SetBackgroundColor(FIELD_COLOR))?;
+            stdout.queue(SetAttribute(Attribute::Bold))?;
+            stdout.queue(SetAttribute(Attribute::Underlined))?;
+            stdout.queue(MoveTo(66, 2))?;
+            stdout.queue(PrintStyledContent("STK".magenta()))?;
+            stdout.queue(SetAttribute(Attribute::Reset))?;
+            stdout.queue(SetBackgroundColor(FIELD_COLOR))?;
+            let bits = stack_index_bits(depth) as usize;
+            for i in 0..depth {
+                stdout.queue(MoveTo(66, 3 + i))?;
+                stdout.queue(PrintStyledContent(format!("{:0>1$b}", i, bits).cyan()))?;
+            }
+        }
+
 
         draw_box((0, 1), (47, 11), "".to_string())?;
         stdout.queue(SetBackgroundColor(FIELD_COLOR))?;
@@ -525,7 +753,7 @@ impl EmulatorState {
 
         draw_box((39, 13), (26, 9), "".to_string())?;
 
-        draw_box((0, 21), (65, 3), "".to_string())?;
+        draw_box((0, 21), (65, 4), "".to_string())?;
 
         self.draw_help()?;
 
@@ -572,313 +800,579 @@ impl EmulatorState {
         stdout.queue(MoveTo(64, 21))?;
         stdout.queue(PrintStyledContent("╣".white()))?;
 
+        if self.cpu.machine().stack > 0 {
+            stdout.queue(MoveTo(64, 1))?;
+            stdout.queue(PrintStyledContent("╦".white()))?;
+            stdout.queue(MoveTo(64, 24))?;
+            stdout.queue(PrintStyledContent("╩".white()))?;
+        }
+
         Ok(())
     }
 
-    /*fn alu_flags(&mut self, result: u16) {
+    /// Executes one instruction, recording what it touched for the next frame.
+    fn execute(&mut self) {
+        let step = self.history.step(&mut self.cpu);
+        self.profile.record(&mut self.cpu, &step);
+        if let Some(trace) = &mut self.trace {
+            if trace.step(&self.cpu, &step).is_err() {
+                self.trace = None;
+                self.log("Trace write failed".to_string());
+            }
+        }
 
-    }*/
+        if let Some(i) = self.current_break.take() {
+            self.pending.mark_rom(i);
+        }
+        self.pending.mark_rom(step.pc);
+        self.pending.mark_write(step.write);
 
-    fn cycle(&mut self) -> Result<()> {
-        self.draw_pc()?;
-        let temp = self.read_from_rom(self.pc);
-        let bin = format!("{temp:b}");
-        let instruction = format!("{bin:0>0$}", 16);
-        let opcode = &instruction[0..4];
-
-        self.executed_instructions += 1;
-
-        match opcode {
-            "0000" => {
-                self.mode = Setup;
-                self.draw_mode()?;
-                self.pc += 1;
-                self.push_log("int".to_string())?;
-            }
-            "0001" => {
-                let dest = u16::from_str_radix(&instruction[4..8], 2).unwrap();
-                let src_a = usize::from_str_radix(&instruction[8..12], 2).unwrap();
-                let src_b = usize::from_str_radix(&instruction[12..16], 2).unwrap();
-
-                self.flg[0] = (self.reg[src_a % 8] + self.reg[src_b % 8]) % 256 == 0;
-                self.flg[1] = (self.reg[src_a % 8] + self.reg[src_b % 8]) % 256 != 0;
-                self.flg[2] = (self.reg[src_a % 8] % 256) + (self.reg[src_b % 8] % 256) & 0x0100 != 0;
-                self.flg[3] = (self.reg[src_a % 8] % 256) + (self.reg[src_b % 8] % 256) & 0x0100 == 0;
-                self.flg[4] = ((self.reg[src_a % 8] % 128) + (self.reg[src_b % 8] % 128) & 0x0080 != 0)
-                            ^ self.flg[2];
-                self.flg[5] = !self.flg[4];
-                self.flg[6] = (self.reg[src_a % 8] + self.reg[src_b % 8]) % 2 == 0;
-                self.flg[7] = (self.reg[src_a % 8] + self.reg[src_b % 8]) % 2 != 0;
-
-                self.write_to_regs(dest % 8, (self.reg[src_a % 8] + self.reg[src_b % 8]) % 256)?;
-
-                self.pc += 1;
-
-                self.push_log(format!("add {}, {}, {}", dest % 8, src_a % 8, src_b % 8))?;
+        let hit = self.debugger.check_write(&step);
+        if step.executed {
+            self.log_buffer.push(Entry::step(&mut self.cpu, &step));
+        }
+
+        match step.interrupt {
+            Some(Interrupt::Print { reg, value }) => self.log(format!("Print r{} = {:02x}", reg, value)),
+            Some(Interrupt::Input { reg }) => {
+                let resume = matches!(self.mode, Automatic(_));
+                self.mode = ManualStep;
+                self.prompt = Some((Prompt::Input { reg, resume }, String::new()));
             }
-            "0010" => {
-                let dest = u16::from_str_radix(&instruction[4..8], 2).unwrap();
-                let src_a = usize::from_str_radix(&instruction[8..12], 2).unwrap();
-                let src_b = usize::from_str_radix(&instruction[12..16], 2).unwrap();
-
-                self.flg[0] = (self.reg[src_a % 8] - self.reg[src_b % 8]) % 256 == 0;
-                self.flg[1] = (self.reg[src_a % 8] - self.reg[src_b % 8]) % 256 != 0;
-                self.flg[2] = (self.reg[src_a % 8] % 256) - (self.reg[src_b % 8] % 256) & 0x0100 != 0;
-                self.flg[3] = (self.reg[src_a % 8] % 256) - (self.reg[src_b % 8] % 256) & 0x0100 == 0;
-                self.flg[4] = ((self.reg[src_a % 8] % 128) - (self.reg[src_b % 8] % 128) & 0x0080 != 0)
-                            ^ self.flg[2];
-                self.flg[5] = !self.flg[4];
-                self.flg[6] = (self.reg[src_a % 8] - self.reg[src_b % 8]) % 2 == 0;
-                self.flg[7] = (self.reg[src_a % 8] - self.reg[src_b % 8]) % 2 != 0;
-
-                self.write_to_regs(dest % 8, (self.reg[src_a % 8] - self.reg[src_b % 8]) % 256)?;
-
-                self.pc += 1;
-
-                self.push_log(format!("sub {}, {}, {}", dest % 8, src_a % 8, src_b % 8))?;
+            Some(Interrupt::Trap { code }) => {
+                if let Automatic(_) = self.mode {
+                    self.mode = ManualStep;
+                }
+                self.log(format!("Trap {} at {:02}", code, step.pc));
             }
-            "0011" => {
-                let dest = u16::from_str_radix(&instruction[4..8], 2).unwrap();
-                let src_a = usize::from_str_radix(&instruction[8..12], 2).unwrap();
-                let src_b = usize::from_str_radix(&instruction[12..16], 2).unwrap();
+            Some(Interrupt::Halt) | None => {}
+        }
+        match step.fault {
+            Some(StackFault::Overflow) => self.log(format!("Stack overflow at {:02}", step.pc)),
+            Some(StackFault::Underflow) => self.log(format!("Stack underflow at {:02}", step.pc)),
+            None => {}
+        }
+        if step.halted && self.prompt.is_none() {
+            self.mode = Setup;
+        }
 
-                self.flg[0] = (self.reg[src_a % 8] & self.reg[src_b % 8]) % 256 == 0;
-                self.flg[1] = (self.reg[src_a % 8] & self.reg[src_b % 8]) % 256 != 0;
-                self.flg[2] = false;
-                self.flg[3] = false;
-                self.flg[4] = false;
-                self.flg[5] = false;
-                self.flg[6] = (self.reg[src_a % 8] & self.reg[src_b % 8]) % 2 == 0;
-                self.flg[7] = (self.reg[src_a % 8] & self.reg[src_b % 8]) % 2 != 0;
+        if let Some(hit) = hit {
+            if let Automatic(_) = self.mode {
+                self.mode = ManualStep;
+            }
+            self.log(hit.to_string());
+        }
+    }
 
-                self.write_to_regs(dest % 8, (self.reg[src_a % 8] & self.reg[src_b % 8]) % 256)?;
+    /// Redraws everything that may have changed since the previous frame.
+    fn draw_frame(&mut self) -> Result<()> {
+        let previous = std::mem::replace(&mut self.highlights, std::mem::take(&mut self.pending));
+        self.draw_highlighted(previous)?;
+        self.show_rom_page(self.cpu.pc)?;
+        self.draw_highlighted(self.highlights)?;
+        if self.heatmap {
+            for idx in 0..self.cpu.machine().rom {
+                self.draw_rom_cell(idx)?;
+            }
+            self.draw_rom_status()?;
+        }
 
-                self.pc += 1;
+        self.draw_pc()?;
+        self.draw_flags()?;
+        self.draw_stack()?;
+        self.draw_log()?;
+        self.draw_help()?;
+        self.draw_game_time()?;
 
-                self.push_log(format!("and {}, {}, {}", dest % 8, src_a % 8, src_b % 8))?;
+        if let Some(trace) = &mut self.trace {
+            if trace.flush().is_err() {
+                self.trace = None;
+                self.push_log("Trace write failed".to_string())?;
             }
-            "0100" => {
-                let dest = u16::from_str_radix(&instruction[4..8], 2).unwrap();
-                let src_a = usize::from_str_radix(&instruction[8..12], 2).unwrap();
-                let src_b = usize::from_str_radix(&instruction[12..16], 2).unwrap();
-
-                self.flg[0] = !(self.reg[src_a % 8] | self.reg[src_b % 8]) % 256 == 0;
-                self.flg[1] = !(self.reg[src_a % 8] | self.reg[src_b % 8]) % 256 != 0;
-                self.flg[2] = false;
-                self.flg[3] = false;
-                self.flg[4] = false;
-                self.flg[5] = false;
-                self.flg[6] = !(self.reg[src_a % 8] | self.reg[src_b % 8]) % 2 == 0;
-                self.flg[7] = !(self.reg[src_a % 8] | self.reg[src_b % 8]) % 2 != 0;
+        }
 
-                self.write_to_regs(dest % 8, !(self.reg[src_a % 8] | self.reg[src_b % 8]) % 256)?;
+        Ok(())
+    }
 
-                self.pc += 1;
+    fn cycle(&mut self) -> Result<()> {
+        self.execute();
+        self.draw_frame()
+    }
 
-                self.push_log(format!("nor {}, {}, {}", dest % 8, src_a % 8, src_b % 8))?;
+    fn load_from_file(&mut self, rom_file_name: &str) -> Result<()> {
+        match fs::read_to_string(Path::new(rom_file_name)) {
+            Ok(v) => {
+                let v = match rom_file_name.ends_with(".asm") {
+                    true => match emulator::assemble(&v, self.cpu.machine()) {
+                        Ok(words) => emulator::asm::to_bin(&words),
+                        Err(e) => {
+                            self.push_log(format!("Asm err. line {}", e.line + 1))?;
+                            return Ok(());
+                        }
+                    },
+                    false => v,
+                };
+                let loaded = self.cpu.load_rom(&v);
+                self.draw_contents()?;
+                if let Err(e) = loaded {
+                    self.push_log(format!("Rom err. line {}", e.line + 1))?;
+                    return Ok(());
+                }
+                self.reset_last_mods()?;
+                self.rom_file_name = Some(rom_file_name.to_string());
+                self.push_log(format!("Loaded {}", rom_file_name))?;
             }
-            "0101" => {
-                let dest = u16::from_str_radix(&instruction[4..8], 2).unwrap();
-                let src_a = usize::from_str_radix(&instruction[8..12], 2).unwrap();
-                let src_b = usize::from_str_radix(&instruction[12..16], 2).unwrap();
-
-                self.flg[0] = (self.reg[src_a % 8] ^ self.reg[src_b % 8]) % 256 == 0;
-                self.flg[1] = (self.reg[src_a % 8] ^ self.reg[src_b % 8]) % 256 != 0;
-                self.flg[2] = false;
-                self.flg[3] = false;
-                self.flg[4] = false;
-                self.flg[5] = false;
-                self.flg[6] = (self.reg[src_a % 8] ^ self.reg[src_b % 8]) % 2 == 0;
-                self.flg[7] = (self.reg[src_a % 8] ^ self.reg[src_b % 8]) % 2 != 0;
-
-                self.write_to_regs(dest % 8, (self.reg[src_a % 8] ^ self.reg[src_b % 8]) % 256)?;
+            Err(_) => {
+                self.push_log("Program not found".to_string())?;
+            }
+        }
+        if let Ok(v) = fs::read_to_string(Path::new(&self.ram_file_name)) {
+            let loaded = self.cpu.load_ram(&v);
+            self.draw_contents()?;
+            if let Err(e) = loaded {
+                self.push_log(format!("Ram err. line {}", e.line + 1))?;
+                return Ok(());
+            }
+            self.reset_last_mods()?;
+            self.push_log("Loaded RAM preset".to_string())?;
+        }
 
-                self.pc += 1;
+        Ok(())
+    }
 
-                self.push_log(format!("xor {}, {}, {}", dest % 8, src_a % 8, src_b % 8))?;
+    /// Keys while the INP editor is open: Up/Down pick a port, Left/Right pick a
+    /// bit, Space toggles it, and typed hex or `0b` binary is stored on Enter.
+    /// Other keys fall through so the program can keep being stepped or run.
+    fn handle_inp_editor_key(&mut self, code: KeyCode) -> Result<bool> {
+        let Some(editor) = &mut self.inp_editor else {
+            return Ok(false);
+        };
+        let port = editor.port;
+        match code {
+            KeyCode::Up => editor.port = (port + 7) % 8,
+            KeyCode::Down => editor.port = (port + 1) % 8,
+            KeyCode::Left => editor.bit = (editor.bit + 1) % 8,
+            KeyCode::Right => editor.bit = (editor.bit + 7) % 8,
+            KeyCode::Char(' ') => self.cpu.inp[port as usize] ^= 1 << editor.bit,
+            KeyCode::Char(c) if c.is_ascii_hexdigit() || c == 'x' => editor.entry.push(c.to_ascii_lowercase()),
+            KeyCode::Backspace => {
+                editor.entry.pop();
             }
-            "0110" => {
-                let dest = u16::from_str_radix(&instruction[4..8], 2).unwrap();
-                let src_a = usize::from_str_radix(&instruction[8..12], 2).unwrap();
+            KeyCode::Enter => {
+                let entry = std::mem::take(&mut editor.entry);
+                match parse_inp_value(&entry) {
+                    Some(value) => self.cpu.inp[port as usize] = value,
+                    None => self.push_log(format!("Bad input '{}'", entry))?,
+                }
+            }
+            KeyCode::Esc | KeyCode::Char('i') => self.inp_editor = None,
+            _ => return Ok(false),
+        }
 
-                self.flg[0] = (self.reg[src_a % 8] >>1) % 256 == 0;
-                self.flg[1] = (self.reg[src_a % 8] >> 1) % 256 != 0;
-                self.flg[2] = false;
-                self.flg[3] = false;
-                self.flg[4] = false;
-                self.flg[5] = false;
-                self.flg[6] = (self.reg[src_a % 8] >> 1) % 2 == 0;
-                self.flg[7] = (self.reg[src_a % 8] >> 1) % 2 != 0;
+        self.draw_inp_cell(port)?;
+        if let Some(editor) = &self.inp_editor {
+            self.draw_inp_cell(editor.port)?;
+        }
+        self.draw_help()?;
 
-                self.write_to_regs(dest % 8, (self.reg[src_a % 8] >> 1) % 256)?;
+        Ok(true)
+    }
 
-                self.pc += 1;
+    /// Handles the debugger keys shared by every mode. Returns whether the key was used.
+    fn handle_debug_key(&mut self, code: KeyCode) -> Result<bool> {
+        if self.inp_editor.is_some() {
+            return self.handle_inp_editor_key(code);
+        }
 
-                self.push_log(format!("rsh {}, {}", dest % 8, src_a % 8))?;
+        if let Some((_, text)) = &mut self.prompt {
+            match code {
+                KeyCode::Char(c) => text.push(c),
+                KeyCode::Backspace => {
+                    text.pop();
+                }
+                KeyCode::Enter => match self.prompt.take().unwrap() {
+                    (Prompt::Watch, text) => match text.parse::<Watchpoint>() {
+                        Ok(watchpoint) if watchpoint.location.fits(&self.cpu) => {
+                            self.debugger.add_watchpoint(watchpoint);
+                            self.push_log(format!("Watching {}", watchpoint))?;
+                        }
+                        _ => self.push_log("Bad watchpoint".to_string())?,
+                    },
+                    (Prompt::Search, text) => {
+                        self.log_buffer.set_search(Some(text));
+                        self.draw_log()?;
+                    }
+                    (Prompt::Input { reg, resume }, text) => match parse_inp_value(&text) {
+                        Some(value) => {
+                            self.cpu.input.push_back(value);
+                            self.push_log(format!("Input r{} = {:02x}", reg, value))?;
+                            if resume {
+                                self.run();
+                                self.draw_mode()?;
+                            }
+                        }
+                        None => self.push_log("Bad input value".to_string())?,
+                    },
+                },
+                KeyCode::Esc => self.prompt = None,
+                _ => {}
             }
-            "0111" => {
-                let src_a = usize::from_str_radix(&instruction[8..12], 2).unwrap();
-                let src_b = usize::from_str_radix(&instruction[12..16], 2).unwrap();
-
-                let a = self.reg[src_a % 8] % 256;
-                let b = self.reg[src_b % 8] % 256;
-
-                self.flg[8] = a > b;
-                self.flg[9] = a <= b;
-                self.flg[10] = a < b;
-                self.flg[11] = a >= b;
-                self.flg[12] = a == b;
-                self.flg[13] = a != b;
-                self.flg[14] = false;
-                self.flg[15] = true;
+            self.draw_help()?;
+            return Ok(true);
+        }
 
-                self.pc += 1;
+        match code {
+            KeyCode::Char('w') => {
+                self.prompt = Some((Prompt::Watch, String::new()));
+                self.draw_help()?;
+                return Ok(true);
+            }
+            KeyCode::Char('h') => {
+                self.heatmap = !self.heatmap;
+                for idx in 0..self.cpu.machine().rom {
+                    self.draw_rom_cell(idx)?;
+                }
+                self.draw_rom_status()?;
+                return Ok(true);
+            }
+            KeyCode::Char('/') => {
+                self.prompt = Some((Prompt::Search, self.log_buffer.search().unwrap_or("").to_string()));
+                self.draw_help()?;
+                return Ok(true);
+            }
+            KeyCode::PageUp | KeyCode::PageDown | KeyCode::Home | KeyCode::End => {
+                match code {
+                    KeyCode::PageUp => self.log_buffer.scroll_by(LOG_ROWS as isize - 1),
+                    KeyCode::PageDown => self.log_buffer.scroll_by(1 - LOG_ROWS as isize),
+                    KeyCode::Home => self.log_buffer.scroll_to_oldest(),
+                    _ => self.log_buffer.scroll_to_newest(),
+                }
+                self.draw_log()?;
+                return Ok(true);
+            }
+            KeyCode::Char('x') => {
+                self.debugger.clear_watchpoints();
+                self.push_log("Watchpoints cleared".to_string())?;
+                return Ok(true);
+            }
+            KeyCode::Char('i') => {
+                self.inp_editor = Some(InpEditor { port: 0, bit: 0, entry: String::new() });
+                self.draw_inp_cell(0)?;
+                self.draw_help()?;
+                return Ok(true);
+            }
+            KeyCode::Tab | KeyCode::BackTab => {
+                let machine = self.cpu.machine();
+                match code {
+                    KeyCode::Tab => self.ram_page = (self.ram_page + 1) % machine.ram.div_ceil(RAM_PAGE),
+                    _ => self.reg_page = (self.reg_page + 1) % machine.registers.div_ceil(REG_PAGE),
+                }
+                self.draw_contents()?;
+                return Ok(true);
+            }
+            _ => {}
+        }
 
-                self.push_log(format!("cmp {}, {}", src_a % 8, src_b % 8))?;
+        let cursor = self.rom_cursor;
+        let rom = self.cpu.machine().rom;
+        self.rom_cursor = match code {
+            KeyCode::Left => (cursor + rom - 1) % rom,
+            KeyCode::Right => (cursor + 1) % rom,
+            KeyCode::Up => (cursor + rom - 8 % rom) % rom,
+            KeyCode::Down => (cursor + 8) % rom,
+            KeyCode::Char('b') => {
+                match self.debugger.toggle_breakpoint(cursor) {
+                    true => self.push_log(format!("Breakpoint {:02} set", cursor))?,
+                    false => self.push_log(format!("Breakpoint {:02} clear", cursor))?,
+                }
+                cursor
             }
-            "1000" => {
-                let dest = u16::from_str_radix(&instruction[4..8], 2).unwrap();
-                let imm = u16::from_str_radix(&instruction[8..16], 2).unwrap();
+            _ => return Ok(false),
+        };
+        self.show_rom_page(self.rom_cursor)?;
+        self.draw_rom_cell(cursor)?;
+        self.draw_rom_cell(self.rom_cursor)?;
 
-                self.write_to_regs(dest % 8, imm % 256)?;
+        Ok(true)
+    }
 
-                self.pc += 1;
+    /// Called before every instruction in `Automatic` mode; drops to `ManualStep`
+    /// when a breakpoint is hit.
+    fn check_break(&mut self) -> Result<bool> {
+        if self.resuming {
+            self.resuming = false;
+            return Ok(false);
+        }
+
+        match self.debugger.check_pc(&self.cpu) {
+            Some(hit) => {
+                self.mode = ManualStep;
+                if let Some(i) = self.current_break.replace(self.cpu.pc) {
+                    self.draw_rom_cell(i)?;
+                }
+                self.show_rom_page(self.cpu.pc)?;
+                self.draw_rom_cell(self.cpu.pc)?;
+                self.draw_pc()?;
+                self.draw_help()?;
+                self.push_log(hit.to_string())?;
 
-                self.push_log(format!("imm {}, {}", dest % 8, imm % 256))?;
+                Ok(true)
             }
-            "1001" => {
-                let dest = u16::from_str_radix(&instruction[4..8], 2).unwrap();
-                let addr = usize::from_str_radix(&instruction[8..16], 2).unwrap();
+            None => Ok(false),
+        }
+    }
 
-                self.write_to_regs(dest % 8, self.ram[addr % 32])?;
+    /// Moves to the next faster or slower speed preset.
+    fn change_speed(&mut self, faster: bool) -> Result<()> {
+        let current = SPEED_PRESETS.iter().position(|&speed| speed == self.speed);
+        let next = match (current, faster) {
+            (Some(idx), true) => (idx + 1).min(SPEED_PRESETS.len() - 1),
+            (Some(idx), false) => idx.saturating_sub(1),
+            (None, true) => SPEED_PRESETS.iter().position(|&speed| speed > self.speed || speed == 0).unwrap(),
+            (None, false) => SPEED_PRESETS.iter().rposition(|&speed| speed != 0 && speed < self.speed).unwrap_or(0),
+        };
+        self.speed = SPEED_PRESETS[next];
+        if let Automatic(_) = self.mode {
+            self.mode = Automatic(self.speed);
+            self.draw_mode()?;
+        }
+        match self.speed {
+            REDSTONE_HZ => self.push_log(format!("Speed {} (redstone)", speed_label(self.speed)))?,
+            _ => self.push_log(format!("Speed {}", speed_label(self.speed)))?,
+        }
 
-                self.pc += 1;
+        Ok(())
+    }
 
-                self.push_log(format!("dml {}, {}", dest % 8, addr % 32))?;
-            }
-            "1010" => {
-                let src = u16::from_str_radix(&instruction[4..8], 2).unwrap();
-                let addr = u16::from_str_radix(&instruction[8..16], 2).unwrap();
+    fn run(&mut self) {
+        self.mode = Automatic(self.speed);
+        self.resuming = self.current_break.is_some();
+    }
 
-                self.write_to_ram(addr % 32, self.reg[(src % 8) as usize])?;
+    fn step_back(&mut self) -> Result<()> {
+        let Some(record) = self.history.undo(&mut self.cpu) else {
+            self.push_log("History empty".to_string())?;
+            return Ok(());
+        };
 
-                self.pc += 1;
+        self.reset_last_mods()?;
+        let pc = self.cpu.pc;
+        self.pending.mark_rom(pc);
+        self.pending.mark_write(record.write);
+        self.log(format!("Back: {}", self.cpu.machine().format(self.cpu.read_from_rom(pc))));
+        self.draw_frame()?;
 
-                self.push_log(format!("dms {}, {}", src % 8, addr % 32))?;
-            }
-            "1011" => {
-                let dest = u16::from_str_radix(&instruction[4..8], 2).unwrap();
-                let ptr = usize::from_str_radix(&instruction[8..12], 2).unwrap();
+        Ok(())
+    }
 
-                self.write_to_regs(dest, self.ram[(self.reg[(ptr % 8)] % 32) as usize])?;
+    fn save_listing(&mut self) -> Result<()> {
+        let listing_path = match &self.rom_file_name {
+            Some(name) => Path::new(name).with_extension("lst"),
+            None => "rom.lst".into(),
+        };
+        match fs::write(&listing_path, emulator::disassemble(&self.cpu.rom, self.cpu.machine())) {
+            Ok(_) => self.push_log(format!("Saved {}", listing_path.display()))?,
+            Err(_) => self.push_log("Listing not saved".to_string())?,
+        }
 
-                self.pc += 1;
+        Ok(())
+    }
 
-                self.push_log(format!("iml {}, {}", dest % 8, ptr % 8))?;
-            }
-            "1100" => {
-                let src = u16::from_str_radix(&instruction[12..16], 2).unwrap();
-                let ptr = u16::from_str_radix(&instruction[8..12], 2).unwrap();
+    /// Where F5 saves and F9 loads: the `--state` file, else `<rom>.state`.
+    fn state_path(&self) -> String {
+        if let Some(name) = &self.state_file_name {
+            return name.clone();
+        }
+        match &self.rom_file_name {
+            Some(name) => Path::new(name).with_extension("state").display().to_string(),
+            None => "emulator.state".to_string(),
+        }
+    }
 
-                self.write_to_ram(self.reg[(ptr % 8) as usize] % 32, self.reg[(src % 8) as usize])?;
+    fn save_state_file(&mut self) -> Result<()> {
+        let path = self.state_path();
+        match fs::write(&path, self.cpu.save_state()) {
+            Ok(_) => self.push_log(format!("Saved {}", path))?,
+            Err(_) => self.push_log("State not saved".to_string())?,
+        }
 
-                self.pc += 1;
+        Ok(())
+    }
 
-                self.push_log(format!("ims {}, {}", ptr % 8, src % 8))?;
-            }
-            "1101" => {
-                let cond = u16::from_str_radix(&instruction[4..8], 2).unwrap();
-                let addr = u16::from_str_radix(&instruction[8..16], 2).unwrap();
-
-                if self.flg[(cond % 16) as usize] {
-                    self.pc = addr % 64;
-                } else {
-                    self.pc += 1;
-                }
+    /// Replaces the whole machine with a saved state and halts there, ready to step.
+    fn load_state_file(&mut self, path: &str) -> Result<()> {
+        let Ok(text) = fs::read_to_string(path) else {
+            self.push_log("State not found".to_string())?;
+            return Ok(());
+        };
+        if let Err(e) = self.cpu.load_state(&text) {
+            self.push_log(format!("State err. line {}", e.line + 1))?;
+            return Ok(());
+        }
 
-                self.push_log(format!("brc {}, {}", cond % 16, addr % 64))?;
-            }
-            "1110" => {
-                let cond = u16::from_str_radix(&instruction[4..8], 2).unwrap();
-                let ptr = u16::from_str_radix(&instruction[12..16], 2).unwrap();
-
-                if self.flg[(cond % 16) as usize] {
-                    self.pc = self.reg[(ptr % 8) as usize] % 64;
-                } else {
-                    self.pc += 1;
-                }
+        self.mode = Setup;
+        self.history.clear();
+        self.state_file_name = Some(path.to_string());
+        self.reset_last_mods()?;
+        self.draw_contents()?;
+        self.draw_help()?;
+        self.push_log(format!("Loaded {}", path))?;
 
-                self.push_log(format!("ibr {}, 0, {}", cond % 16, ptr % 8))?;
-            }
-            "1111" => {
-                let addr = u16::from_str_radix(&instruction[4..16], 2).unwrap();
+        Ok(())
+    }
 
-                self.pc = addr % 64;
+    /// Lists `.bin`, `.asm` and `.state` files in the working directory, except the RAM preset.
+    fn open_picker(&mut self) -> Result<()> {
+        let mut entries: Vec<String> = fs::read_dir("./")?
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter(|name| [".bin", ".asm", ".state"].iter().any(|ext| name.ends_with(ext)) && *name != self.ram_file_name)
+            .collect();
+        entries.sort();
+
+        if entries.is_empty() {
+            self.push_log("No programs found".to_string())?;
+            return Ok(());
+        }
+        let selected = self.rom_file_name.as_ref()
+            .and_then(|name| entries.iter().position(|entry| entry == name))
+            .unwrap_or(0);
+        self.picker = Some(FilePicker { entries, selected });
+        self.draw_picker()?;
+        self.draw_help()?;
 
-                self.push_log(format!("jmp {}", addr % 64))?;
-            }
-            _ => {
-                self.pc += 1;
+        Ok(())
+    }
 
-                self.push_log("unknown opcode".to_string())?;
-            }
-        }
+    fn draw_picker(&self) -> Result<()> {
+        let mut stdout = stdout();
+        let Some(picker) = &self.picker else {
+            return Ok(());
+        };
 
-        self.draw_flags()?;
+        let first = picker.selected.saturating_sub(6);
+        for row in 0..7 {
+            let idx = first + row;
+            let name = picker.entries.get(idx).map_or("", String::as_str);
+            let text = format!("{: <22}", name.chars().take(22).collect::<String>()).white();
+            stdout.queue(MoveTo(41, 14 + row as u16))?;
+            match idx == picker.selected {
+                true => stdout.queue(PrintStyledContent(text.on(CURSOR_COLOR)))?,
+                false => stdout.queue(PrintStyledContent(text))?,
+            };
+        }
 
         Ok(())
     }
 
-    fn load_from_file(&mut self, rom_file_name: &str) -> Result<()> {
-        match fs::read_to_string(Path::new(rom_file_name)) {
-            Ok(v) => {
-                let lines: Vec<String> = v.split('\n').map(|x| x.trim().to_string()).collect();
-                for (idx, line) in lines.iter().enumerate() {
-                    if line.len() == 16 {
-                        match u32::from_str_radix(line, 2) {
-                            Ok(p) => {
-                                self.write_to_rom(idx as u16, p)?;
-                            }
-                            Err(_) => {
-                                self.push_log("Rom init. corrupted".to_string())?;
-                                return Ok(());
-                            }
-                        }
+    /// Keys while the file picker is open. Every key is consumed.
+    fn handle_picker_key(&mut self, code: KeyCode) -> Result<()> {
+        let Some(picker) = &mut self.picker else {
+            return Ok(());
+        };
+
+        match code {
+            KeyCode::Up => picker.selected = (picker.selected + picker.entries.len() - 1) % picker.entries.len(),
+            KeyCode::Down => picker.selected = (picker.selected + 1) % picker.entries.len(),
+            KeyCode::Enter => {
+                let name = picker.entries[picker.selected].clone();
+                self.picker = None;
+                match name.ends_with(".state") {
+                    true => self.load_state_file(&name)?,
+                    false => {
+                        self.full_reset()?;
+                        self.load_from_file(&name)?;
                     }
                 }
-                self.reset_last_mods()?;
-                self.push_log(format!("Loaded {}", rom_file_name))?;
             }
-            Err(_) => {
-                self.push_log("Program not found".to_string())?;
-            }
-        }
-        match fs::read_to_string(Path::new("ram.bin")) {
-            Ok(v) => {
-                let lines: Vec<String> = v.split('\n').map(|x| x.trim().to_string()).collect();
-                for (idx, line) in lines.iter().enumerate() {
-                    if line.len() == 8 {
-                        match u16::from_str_radix(line, 2) {
-                            Ok(p) => {
-                                self.write_to_ram(idx as u16, p)?;
-                            }
-                            Err(_) => {
-                                self.push_log("Ram init. corrupted".to_string())?;
-                                return Ok(());
-                            }
-                        }
-                    }
-                }
-                self.reset_last_mods()?;
-                self.push_log("Loaded RAM preset".to_string())?;
+            KeyCode::Esc | KeyCode::Char('l') => {
+                self.picker = None;
+                self.draw_log()?;
             }
-            Err(_) => {}
+            _ => {}
         }
+        self.draw_picker()?;
+        self.draw_help()?;
 
         Ok(())
     }
 }
 
+/// Heatmap background for a ROM cell, on a log scale up to the hottest cell.
+fn heat_color(hits: u64, max: u64) -> Color {
+    if hits == 0 {
+        return FIELD_COLOR;
+    }
+    let top = HEAT_COLORS.len() - 1;
+    let level = match max > 1 {
+        true => ((hits as f64).ln() / (max as f64).ln() * top as f64).round() as usize,
+        false => top,
+    };
+    HEAT_COLORS[level.min(top)]
+}
+
+/// Short form of a target frequency for the six-character mode field.
+fn speed_label(speed: u16) -> String {
+    match speed {
+        0 => "MAX".to_string(),
+        1..=999 => format!("{}Hz", speed),
+        _ => format!("{}kHz", speed / 1000),
+    }
+}
+
+/// Shows an instructions-per-second figure in the title bar.
+fn draw_frequency(frequency: f64) -> Result<()> {
+    let mut stdout = stdout();
+
+    let freq_string = format!("{:.2}", frequency);
+    stdout.queue(MoveTo(51, 0))?;
+    stdout.queue(SetBackgroundColor(Color::Magenta))?;
+    stdout.queue(SetAttribute(Attribute::Bold))?;
+    stdout.queue(SetAttribute(Attribute::Underlined))?;
+    stdout.queue(PrintStyledContent(format!("{: >10} Hz", freq_string).white()))?;
+    stdout.queue(SetBackgroundColor(BG_COLOR))?;
+    stdout.queue(SetAttribute(Attribute::Reset))?;
+
+    Ok(())
+}
+
+/// `" 40-7f "` for the page of a panel showing `size` of `total` cells, if
+/// the panel has more than one page.
+fn page_label(page: u16, size: u16, total: u16) -> Option<String> {
+    match total > size {
+        true => Some(format!(" {:02x}-{:02x} ", page * size, (page + 1) * size - 1)),
+        false => None,
+    }
+}
+
+fn draw_help_row(row: u16, entries: &[(&str, &str)]) -> Result<()> {
+    let mut stdout = stdout();
+
+    stdout.queue(MoveTo(2, row))?;
+    let mut width = 0;
+    for (key, action) in entries {
+        let text = format!(" {}  ", action);
+        width += key.len() + text.len();
+        stdout.queue(PrintStyledContent(key.cyan()))?;
+        stdout.queue(PrintStyledContent(text.white()))?;
+    }
+    stdout.queue(Print(" ".repeat(HELP_WIDTH.saturating_sub(width))))?;
+
+    Ok(())
+}
+
+/// Parses a value typed into the INP editor: `0b` binary, otherwise hex with an optional `0x`.
+fn parse_inp_value(text: &str) -> Option<u8> {
+    match text.strip_prefix("0b") {
+        Some(bin) => u8::from_str_radix(bin, 2).ok(),
+        None => u8::from_str_radix(text.strip_prefix("0x").unwrap_or(text), 16).ok(),
+    }
+}
+
+/// Binary digits in the call stack panel's slot numbers.
+fn stack_index_bits(depth: u16) -> u16 {
+    (u16::BITS - depth.saturating_sub(1).leading_zeros()).max(1) as u16
+}
+
 fn draw_box((x_pos, y_pos): (u16, u16), (x_size, y_size): (u16, u16), title: String) -> Result<()> {
     let mut stdout = stdout();
 
@@ -900,76 +1394,578 @@ fn draw_box((x_pos, y_pos): (u16, u16), (x_size, y_size): (u16, u16), title: Str
     Ok(())
 }
 
+/// Loads `--machine`: a preset name, or else a machine file.
+fn load_machine(name: &str) -> Machine {
+    if let Some(machine) = Machine::preset(name) {
+        return machine;
+    }
+    let text = match fs::read_to_string(name) {
+        Ok(text) => text,
+        Err(_) => {
+            let presets: Vec<&str> = machine::PRESETS.iter().map(|(preset, ..)| *preset).collect();
+            eprintln!("{}: not a machine file or preset ({})", name, presets.join(", "));
+            process::exit(2);
+        }
+    };
+    match text.parse() {
+        Ok(machine) => machine,
+        Err(e) => {
+            eprintln!("{}:{}: {}", name, e.line + 1, e.message);
+            process::exit(2);
+        }
+    }
+}
+
+/// Loads `--isa`: an instruction set table, named after the file.
+fn load_isa(path: &str) -> Isa {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            process::exit(2);
+        }
+    };
+    match text.parse::<Isa>() {
+        Ok(mut isa) => {
+            isa.name = Path::new(path).file_stem().map_or(isa.name, |stem| stem.to_string_lossy().into_owned());
+            isa
+        }
+        Err(e) => {
+            eprintln!("{}:{}: {}", path, e.line + 1, e.message);
+            process::exit(2);
+        }
+    }
+}
+
+/// Takes `--machine <name>` and `--isa <file>` out of a subcommand's arguments,
+/// leaving the positional ones.
+fn split_machine(args: &[String]) -> (Machine, Vec<String>) {
+    let mut machine = Machine::default();
+    let mut isa = None;
+    let mut rest = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--machine" => machine = load_machine(args.next().unwrap_or_else(|| usage())),
+            "--isa" => isa = Some(load_isa(args.next().unwrap_or_else(|| usage()))),
+            _ => rest.push(arg.clone()),
+        }
+    }
+    if let Some(isa) = isa {
+        machine.isa = isa;
+    }
+    (machine, rest)
+}
+
+/// `emulator asm <source.asm> [output.bin]` assembles a program without starting the TUI.
+fn assemble_file(args: &[String]) -> Result<()> {
+    let (machine, args) = split_machine(args);
+    let Some(source_path) = args.first() else {
+        eprintln!("usage: emulator asm <source.asm> [output.bin] [--machine <name|file>] [--isa <file>]");
+        process::exit(2);
+    };
+    let output_path = match args.get(1) {
+        Some(path) => path.into(),
+        None => Path::new(source_path).with_extension("bin"),
+    };
+
+    let source = fs::read_to_string(source_path)?;
+    match emulator::assemble(&source, &machine) {
+        Ok(words) => {
+            fs::write(&output_path, emulator::asm::to_bin(&words))?;
+            println!("{} words written to {}", words.len(), output_path.display());
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("{}:{}: {}", source_path, e.line + 1, e.message);
+            process::exit(1);
+        }
+    }
+}
+
+/// `emulator disasm <rom.bin> [output.asm]` prints or writes a listing of a ROM image.
+fn disassemble_file(args: &[String]) -> Result<()> {
+    let (machine, args) = split_machine(args);
+    let Some(rom_path) = args.first() else {
+        eprintln!("usage: emulator disasm <rom.bin> [output.asm] [--machine <name|file>] [--isa <file>]");
+        process::exit(2);
+    };
+
+    let mut cpu = Cpu::with_machine(machine);
+    if let Err(e) = cpu.load_rom(&fs::read_to_string(rom_path)?) {
+        eprintln!("{}: {}", rom_path, e);
+        process::exit(1);
+    }
+
+    let listing = emulator::disassemble(&cpu.rom, cpu.machine());
+    match args.get(1) {
+        Some(output_path) => fs::write(output_path, listing)?,
+        None => print!("{}", listing),
+    }
+
+    Ok(())
+}
+
+fn parse_trace_format(text: &str) -> trace::Format {
+    match text {
+        "text" => trace::Format::Text,
+        "binary" => trace::Format::Binary,
+        _ => usage(),
+    }
+}
+
+/// `emulator trace <trace> ...` prints the records of a text or binary trace that
+/// match the filters, or converts them to another file.
+fn read_trace(args: &[String]) -> Result<()> {
+    let mut path = None;
+    let mut filter = trace::Filter::default();
+    let mut output = None;
+    let mut format = trace::Format::Text;
+    let mut machine = Machine::default();
+    let mut isa = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--pc" => {
+                let range = value();
+                let bound = |text: &str| text.parse::<u16>().ok().filter(|&pc| pc < machine::MAX_ROM).unwrap_or_else(|| usage());
+                filter.pc = Some(match range.split_once("..") {
+                    Some((from, to)) => bound(from)..=bound(to),
+                    None => bound(&range)..=bound(&range),
+                });
+            }
+            "--op" => filter.mnemonics.extend(value().split(',').map(|m| m.trim().to_string())),
+            "--output" => output = Some(value()),
+            "--format" => format = parse_trace_format(&value()),
+            "--machine" => machine = load_machine(&value()),
+            "--isa" => isa = Some(load_isa(&value())),
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg.clone()),
+            _ => usage(),
+        }
+    }
+    let Some(path) = path else { usage() };
+    if let Some(isa) = isa {
+        machine.isa = isa;
+    }
+
+    let records = match trace::read(&fs::read(&path)?) {
+        Ok(records) => records,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }
+    };
+    let records = records.into_iter().filter(|record| filter.matches(record, &machine));
+
+    match output {
+        Some(output) => {
+            let mut writer = TraceWriter::new(BufWriter::new(File::create(output)?), format, &machine)?;
+            for record in records {
+                writer.record(record)?;
+            }
+            writer.flush()?;
+        }
+        None => {
+            let mut stdout = BufWriter::new(stdout());
+            for record in records {
+                writeln!(stdout, "{}", record.text(&machine))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+const USAGE: &str = "usage: emulator [rom.bin|rom.asm] [--ram <preset.bin>] [--start halted|step|run] [--speed <hz>] [--ticks <costs>] [--state <file>]
+                [--vectors <map>] [--input <bytes>] [--trace <file>] [--trace-format text|binary] [--log-length <n>] [--profile] [--dir <path>]
+                [--machine <name|file>] [--isa <file>]
+       emulator --headless <rom.bin|rom.asm> [--ram <preset.bin>] [--state <file>] [--cycles <n>] [--ticks <costs>]
+                [--vectors <map>] [--input <bytes>] [--json] [--expect <cond>]... [--save-state <file>]
+                [--trace <file>] [--trace-format text|binary] [--profile] [--machine <name|file>]
+                [--isa <file>]
+       emulator asm <source.asm> [output.bin] [--machine <name|file>] [--isa <file>]
+       emulator disasm <rom.bin> [output.asm] [--machine <name|file>] [--isa <file>]
+       emulator trace <trace> [--pc <from>..<to>] [--op <mnemonic>[,...]] [--output <file>] [--format text|binary]
+                [--machine <name|file>] [--isa <file>]
+
+Machines: nano (default; 64 words ROM, 32 bytes RAM, 8 registers), wide (256, 128, 8), wide16 (256, 128, 16),
+or a machine file (see src/machine.rs; `stack = N` adds cal/ret, `signed_compare = true` adds cms). --isa replaces the stock instruction set with a table (see src/isa.rs).";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum StartMode {
+    Halted,
+    Step,
+    Run,
+}
+
+/// Command-line options shared by the TUI and the headless runner.
+struct Options {
+    rom: Option<String>,
+    ram: Option<String>,
+    start: StartMode,
+    /// Instructions per second in `Automatic` mode; 0 runs unthrottled.
+    speed: u16,
+    /// Redstone ticks per instruction class, e.g. `alu=8,mem=14`.
+    ticks: TickCosts,
+    /// Save state restored after the ROM and RAM preset are loaded.
+    state: Option<String>,
+    /// Handler remappings for `int` vectors, e.g. `5=print,6=trap`.
+    vectors: Vectors,
+    /// Bytes queued for the `input` interrupt.
+    input: Vec<u8>,
+    /// Machine preset or file, loaded after `--dir` like the other paths.
+    machine: Option<String>,
+    /// Instruction set table replacing the machine's stock one.
+    isa: Option<String>,
+    trace: Option<String>,
+    trace_format: trace::Format,
+    dir: Option<String>,
+
+    headless: bool,
+    max_cycles: usize,
+    log_length: usize,
+    /// Print a profile report when the TUI quits or the headless run ends.
+    profile: bool,
+    json: bool,
+    save_state: Option<String>,
+    expectations: Vec<Expectation>,
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn parse_options(args: &[String]) -> Options {
+    let mut options = Options {
+        rom: None,
+        ram: None,
+        start: StartMode::Halted,
+        speed: 0,
+        ticks: TickCosts::default(),
+        state: None,
+        vectors: Vectors::default(),
+        input: Vec::new(),
+        machine: None,
+        isa: None,
+        trace: None,
+        trace_format: trace::Format::Text,
+        dir: None,
+
+        headless: false,
+        max_cycles: 1_000_000,
+        log_length: LOG_LENGTH,
+        profile: false,
+        json: false,
+        save_state: None,
+        expectations: Vec::new(),
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--ram" => options.ram = Some(value()),
+            "--dir" => options.dir = Some(value()),
+            "--state" => options.state = Some(value()),
+            "--save-state" => options.save_state = Some(value()),
+            "--trace" => options.trace = Some(value()),
+            "--trace-format" => options.trace_format = parse_trace_format(&value()),
+            "--start" => options.start = match value().as_str() {
+                "halted" => StartMode::Halted,
+                "step" => StartMode::Step,
+                "run" => StartMode::Run,
+                _ => usage(),
+            },
+            "--speed" => options.speed = value().parse().unwrap_or_else(|_| usage()),
+            "--ticks" => match value().parse() {
+                Ok(ticks) => options.ticks = ticks,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(2);
+                }
+            },
+            "--vectors" => match value().parse() {
+                Ok(vectors) => options.vectors = vectors,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(2);
+                }
+            },
+            "--input" => match interrupt::parse_input(&value()) {
+                Ok(input) => options.input.extend(input),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(2);
+                }
+            },
+            "--machine" => options.machine = Some(value()),
+            "--isa" => options.isa = Some(value()),
+            "--headless" => options.headless = true,
+            "--cycles" => options.max_cycles = value().parse().unwrap_or_else(|_| usage()),
+            "--log-length" => options.log_length = value().parse().unwrap_or_else(|_| usage()),
+            "--json" => options.json = true,
+            "--profile" => options.profile = true,
+            "--expect" => match value().parse() {
+                Ok(expectation) => options.expectations.push(expectation),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(2);
+                }
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ if options.rom.is_none() && !arg.starts_with("--") => options.rom = Some(arg.clone()),
+            _ => usage(),
+        }
+    }
+
+    options
+}
+
+/// `emulator --headless <rom> ...` runs a program until it halts, waits for
+/// input that was not given with `--input`, or hits the cycle limit, and prints
+/// the final state after any `print` and `trap` output. Exits with 1 if any expectation does not hold.
+/// With `--state`, the ROM argument may be left out.
+fn run_headless(options: &Options, machine: Machine) -> Result<()> {
+    if options.rom.is_none() && options.state.is_none() {
+        usage();
+    }
+
+    let mut cpu = Cpu::with_machine(machine);
+    cpu.tick_costs = options.ticks;
+    cpu.vectors = options.vectors;
+    cpu.input.extend(&options.input);
+    if let Some(rom_path) = &options.rom {
+        let source = fs::read_to_string(rom_path)?;
+        let rom = match rom_path.ends_with(".asm") {
+            true => match emulator::assemble(&source, cpu.machine()) {
+                Ok(words) => emulator::asm::to_bin(&words),
+                Err(e) => {
+                    eprintln!("{}:{}: {}", rom_path, e.line + 1, e.message);
+                    process::exit(1);
+                }
+            },
+            false => source,
+        };
+        if let Err(e) = cpu.load_rom(&rom) {
+            eprintln!("{}: {}", rom_path, e);
+            process::exit(1);
+        }
+    }
+    if let Some(ram_path) = &options.ram {
+        if let Err(e) = cpu.load_ram(&fs::read_to_string(ram_path)?) {
+            eprintln!("{}: {}", ram_path, e);
+            process::exit(1);
+        }
+    }
+
+    if let Some(state_path) = &options.state {
+        if let Err(e) = cpu.load_state(&fs::read_to_string(state_path)?) {
+            eprintln!("{}:{}: {}", state_path, e.line + 1, e.message);
+            process::exit(1);
+        }
+    }
+
+    let mut trace = match &options.trace {
+        Some(trace_path) => Some(TraceWriter::new(BufWriter::new(File::create(trace_path)?), options.trace_format, cpu.machine())?),
+        None => None,
+    };
+    let mut profile = Profile::new();
+    let status = batch::run_with(&mut cpu, options.max_cycles, |cpu, step| {
+        if options.profile {
+            profile.record(cpu, step);
+        }
+        // Program output goes before the report, and to stderr when the report is JSON.
+        if let Some(interrupt @ (Interrupt::Print { .. } | Interrupt::Trap { .. })) = step.interrupt {
+            match options.json {
+                true => eprintln!("{:02} {}", step.pc, interrupt),
+                false => println!("{:02} {}", step.pc, interrupt),
+            }
+        }
+        match &mut trace {
+            Some(trace) => trace.step(cpu, step),
+            None => Ok(()),
+        }
+    })?;
+    if let Some(trace) = &mut trace {
+        trace.flush()?;
+    }
+    if let Some(state_path) = &options.save_state {
+        fs::write(state_path, cpu.save_state())?;
+    }
+    match options.json {
+        true => print!("{}", batch::json_report(&cpu, status)),
+        false => print!("{}", batch::text_report(&cpu, status)),
+    }
+    if options.profile {
+        let report = profile.report(&cpu, REPORT_LENGTH);
+        match options.json {
+            true => eprint!("{}", report),
+            false => print!("{}", report),
+        }
+    }
+
+    let mut failed = false;
+    for expectation in &options.expectations {
+        if let Err(found) = expectation.check(&cpu, status) {
+            let found: Vec<String> = found.iter().map(|v| format!("0x{:02x}", v)).collect();
+            eprintln!("expectation failed: {} (found {})", expectation, found.join(","));
+            failed = true;
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+
+    Ok(())
+}
+
 fn main() -> Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("asm") => return assemble_file(&args[1..]),
+        Some("disasm") => return disassemble_file(&args[1..]),
+        Some("trace") => return read_trace(&args[1..]),
+        _ => {}
+    }
+
+    let options = parse_options(&args);
+    if let Some(dir) = &options.dir {
+        if let Err(e) = env::set_current_dir(dir) {
+            eprintln!("{}: {}", dir, e);
+            process::exit(2);
+        }
+    }
+    let mut machine = options.machine.as_deref().map_or_else(Machine::default, load_machine);
+    if let Some(path) = &options.isa {
+        machine.isa = load_isa(path);
+    }
+    if options.headless {
+        return run_headless(&options, machine);
+    }
+    for path in options.rom.iter().chain(&options.ram).chain(&options.state) {
+        if !Path::new(path).is_file() {
+            eprintln!("{}: file not found", path);
+            process::exit(2);
+        }
+    }
+
     let size_restore: (u16, u16) = terminal::size()?;
 
     let mut stdout = stdout();
     enable_raw_mode()?;
 
     let mut emulator: EmulatorState = EmulatorState {
-        rom: [0; 64],
-        ram: [0; 32],
-        reg: [0; 8],
-        inp: [0; 8],
-        out: [0; 8],
-        flg: [false, false, false, false, false, false, false, false,
-            false, false, false, false, false, false, false, true],
-        pc: 0,
+        log_buffer: Log::new(options.log_length, &machine),
+        cpu: Cpu::with_machine(machine),
+        rom_file_name: None,
+        ram_file_name: options.ram.clone().unwrap_or_else(|| "ram.bin".to_string()),
+        state_file_name: None,
+        trace: None,
 
         mode: Setup,
-        log_buffer: Default::default(),
-
-        executed_instructions: 0,
-
-        current_rom_read: None,
-        current_ram_write: None,
-        current_reg_write: None,
+        speed: options.speed,
+
+        debugger: Debugger::new(),
+        history: History::new(HISTORY_LENGTH),
+        profile: Profile::new(),
+        heatmap: false,
+        rom_cursor: 0,
+        rom_page: 0,
+        ram_page: 0,
+        reg_page: 0,
+        resuming: false,
+        prompt: None,
+        inp_editor: None,
+        picker: None,
+
+        highlights: Highlights::default(),
+        pending: Highlights::default(),
+        current_break: None,
     };
+    emulator.cpu.tick_costs = options.ticks;
+    emulator.cpu.vectors = options.vectors;
+    emulator.cpu.input.extend(&options.input);
+    if let Some(trace_path) = &options.trace {
+        emulator.trace = Some(TraceWriter::new(BufWriter::new(File::create(trace_path)?), options.trace_format, emulator.cpu.machine())?);
+    }
 
     emulator.program_reset()?;
 
     emulator.draw_layout()?;
     emulator.draw_contents()?;
 
-    let mut path_idx = 0;
-    let mut now = Instant::now();
+    if let Some(rom) = &options.rom {
+        emulator.load_from_file(rom)?;
+    }
+    if let Some(state) = &options.state {
+        emulator.load_state_file(state)?;
+    }
+    match options.start {
+        StartMode::Halted => {}
+        StartMode::Step => emulator.mode = ManualStep,
+        StartMode::Run => emulator.run(),
+    }
+    emulator.draw_mode()?;
+    emulator.draw_help()?;
+    stdout.flush()?;
 
-    let mut delay: u128 = 0;
+    let mut pacer = Pacer::new();
+    let mut now = Instant::now();
 
     loop {
-        if terminal::size()? != WINDOW_SIZE {
-            stdout.queue(SetSize(WINDOW_SIZE.0, WINDOW_SIZE.1))?;
+        let window_size = emulator.window_size();
+        if terminal::size()? != window_size {
+            stdout.queue(SetSize(window_size.0, window_size.1))?;
         }
 
         if poll(Duration::from_micros(0))? {
             if let Event::Key(key) = read()? {
+                if key.kind == KeyEventKind::Press && emulator.picker.is_some() {
+                    emulator.handle_picker_key(key.code)?;
+                    stdout.flush()?;
+                    continue;
+                }
+                let typing = emulator.prompt.is_some() || emulator.inp_editor.is_some();
+                if key.kind == KeyEventKind::Press && !typing && matches!(key.code, KeyCode::F(5) | KeyCode::F(9)) {
+                    match key.code {
+                        KeyCode::F(5) => emulator.save_state_file()?,
+                        _ => emulator.load_state_file(&emulator.state_path())?,
+                    }
+                    stdout.flush()?;
+                    continue;
+                }
+                if key.kind == KeyEventKind::Press && !typing && matches!(key.code, KeyCode::Char('+' | '-')) {
+                    emulator.change_speed(key.code == KeyCode::Char('+'))?;
+                    stdout.flush()?;
+                    continue;
+                }
+                if key.kind == KeyEventKind::Press && emulator.handle_debug_key(key.code)? {
+                    stdout.flush()?;
+                    continue;
+                }
                 match &emulator.mode {
                     Setup => {
                         match (key.code, key.kind) {
                             (KeyCode::Char('l'), KeyEventKind::Press) => {
-                                emulator.full_reset()?;
-
-                                let paths: Vec<OsString> = fs::read_dir("./")
-                                    .unwrap()
-                                    .map(|x| x.unwrap().file_name())
-                                    .filter(|x|
-                                        x.to_str().unwrap().ends_with(".bin")
-                                            && x.to_str().unwrap() != "ram.bin"
-                                    )
-                                    .collect();
-                                if path_idx < paths.len() {
-                                    emulator.load_from_file(paths[path_idx].to_str().unwrap())?;
-                                }
-                                path_idx += 1;
-                                if path_idx >= paths.len() {
-                                    path_idx = 0;
-                                }
+                                emulator.open_picker()?;
+                            }
+                            (KeyCode::Char('d'), KeyEventKind::Press) => {
+                                emulator.save_listing()?;
+                            }
+                            (KeyCode::Char('z'), KeyEventKind::Press) => {
+                                emulator.step_back()?;
                             }
                             (KeyCode::Char('c'), KeyEventKind::Press) => {
                                 emulator.full_reset()?;
                             }
                             (KeyCode::Char('r'), KeyEventKind::Press) => {
-                                emulator.mode = Automatic(0);
+                                emulator.run();
                             }
                             (KeyCode::Char('s'), KeyEventKind::Press) => {
                                 emulator.mode = ManualStep;
@@ -980,6 +1976,10 @@ fn main() -> Result<()> {
                                 stdout.queue(MoveTo(0,0))?;
                                 stdout.queue(Clear(ClearType::Purge))?;
                                 stdout.queue(Clear(ClearType::All))?;
+                                stdout.flush()?;
+                                if options.profile {
+                                    print!("{}", emulator.profile.report(&emulator.cpu, REPORT_LENGTH));
+                                }
                                 return Ok(())
                             }
                             _ => {}
@@ -990,19 +1990,16 @@ fn main() -> Result<()> {
                             (KeyCode::Char('c'), KeyEventKind::Press) => {
                                 emulator.program_reset()?;
                             }
+                            (KeyCode::Char('r'), KeyEventKind::Press) => {
+                                emulator.run();
+                            }
+                            (KeyCode::Char('z'), KeyEventKind::Press) => {
+                                emulator.step_back()?;
+                            }
                             (KeyCode::Char('s'), KeyEventKind::Press) => {
                                 emulator.cycle()?;
-                                let elapsed_time = now.elapsed().as_micros();
+                                draw_frequency(1.0 / now.elapsed().as_secs_f64())?;
                                 now = Instant::now();
-                                let frequency: f64 = 1000000f64 / elapsed_time as f64;
-                                let freq_string = format!("{:.2}", frequency);
-                                stdout.queue(MoveTo(51, 0))?;
-                                stdout.queue(SetBackgroundColor(Color::Magenta))?;
-                                stdout.queue(SetAttribute(Attribute::Bold))?;
-                                stdout.queue(SetAttribute(Attribute::Underlined))?;
-                                stdout.queue(PrintStyledContent(format!("{: >10} Hz", freq_string).white()))?;
-                                stdout.queue(SetBackgroundColor(BG_COLOR))?;
-                                stdout.queue(SetAttribute(Attribute::Reset))?;
                             }
                             _ => {}
                         }
@@ -1026,28 +2023,33 @@ fn main() -> Result<()> {
                 stdout.queue(cursor::Hide)?;
                 emulator.draw_layout()?;
                 emulator.draw_contents()?;
+                emulator.draw_picker()?;
                 stdout.flush()?;
             }
             while poll(Duration::from_millis(0))? {
                 read()?;
             }
         }
-        if let Automatic(_) = emulator.mode {
-            emulator.cycle()?;
-            delay += 1;
-            let elapsed_time = now.elapsed().as_micros();
-            now = Instant::now();
-            if delay % 100 == 0 {
-                let frequency: f64 = 1000000f64 / elapsed_time as f64;
-                let freq_string = format!("{:.2}", frequency);
-                stdout.queue(MoveTo(51, 0))?;
-                stdout.queue(SetBackgroundColor(Color::Magenta))?;
-                stdout.queue(SetAttribute(Attribute::Bold))?;
-                stdout.queue(SetAttribute(Attribute::Underlined))?;
-                stdout.queue(PrintStyledContent(format!("{: >10} Hz", freq_string).white()))?;
-                stdout.queue(SetBackgroundColor(BG_COLOR))?;
-                stdout.queue(SetAttribute(Attribute::Reset))?;
+        if let Automatic(speed) = emulator.mode {
+            let frame_start = Instant::now();
+            let frame_end = frame_start + FRAME_TIME;
+            let executed = emulator.cpu.executed_instructions;
+            while Instant::now() < frame_end {
+                if speed > 0 && !pacer.ready(speed) {
+                    thread::sleep(pacer.next.min(frame_end).saturating_duration_since(Instant::now()));
+                    continue;
+                }
+                if emulator.check_break()? {
+                    break;
+                }
+                emulator.execute();
+                if !matches!(emulator.mode, Automatic(_)) {
+                    break;
+                }
             }
+            emulator.draw_frame()?;
+            let executed = emulator.cpu.executed_instructions.saturating_sub(executed);
+            draw_frequency(executed as f64 / frame_start.elapsed().as_secs_f64())?;
             stdout.flush()?;
         }
     }
@@ -0,0 +1,174 @@
+//! Execution profile: where a program spends its instructions and game ticks.
+//!
+//! Loops are found from back-edges, taken jumps whose target is at or before
+//! the jump itself; the loop body is the ROM range between the two. Stepping
+//! backwards does not remove counts, so profile a clean run for exact numbers.
+
+use std::collections::BTreeMap;
+
+use crate::{cpu::{Cpu, Step}, instruction::Instruction, machine::MAX_ROM};
+
+pub struct Profile {
+    hits: [u64; MAX_ROM as usize],
+    ticks: [u64; MAX_ROM as usize],
+    /// Executions per instruction form, keyed by its index in the machine's ISA.
+    forms: BTreeMap<usize, u64>,
+    taken: [u64; MAX_ROM as usize],
+    not_taken: [u64; MAX_ROM as usize],
+    /// Taken back-edges, keyed by (jump address, target).
+    back_edges: BTreeMap<(u16, u16), u64>,
+}
+
+/// A loop found from a back-edge, with the totals of its body.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Loop {
+    pub start: u16,
+    pub end: u16,
+    pub iterations: u64,
+    pub instructions: u64,
+    pub ticks: u64,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Profile {
+            hits: [0; MAX_ROM as usize],
+            ticks: [0; MAX_ROM as usize],
+            forms: BTreeMap::new(),
+            taken: [0; MAX_ROM as usize],
+            not_taken: [0; MAX_ROM as usize],
+            back_edges: BTreeMap::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Counts one executed instruction; `cpu` is the state after `step`. Steps
+    /// that did not execute are skipped.
+    pub fn record(&mut self, cpu: &mut Cpu, step: &Step) {
+        if !step.executed {
+            return;
+        }
+        let pc = step.pc as usize;
+        let word = step.word;
+        let instruction = cpu.instruction(step.pc);
+
+        self.hits[pc] += 1;
+        self.ticks[pc] += cpu.tick_costs.cost(&instruction) as u64;
+        *self.forms.entry(cpu.machine().isa.index(word)).or_insert(0) += 1;
+
+        // Branches leave the flags alone, so the condition still reads as it did.
+        let taken = match instruction {
+            Instruction::Brc { cond, .. } | Instruction::Ibr { cond, .. } => {
+                match cpu.flg[cond as usize] {
+                    true => self.taken[pc] += 1,
+                    false => self.not_taken[pc] += 1,
+                }
+                cpu.flg[cond as usize]
+            }
+            Instruction::Jmp { .. } => true,
+            _ => false,
+        };
+        let target = cpu.pc;
+        if taken && target <= step.pc {
+            *self.back_edges.entry((step.pc, target)).or_insert(0) += 1;
+        }
+    }
+
+    pub fn hits(&self, addr: u16) -> u64 {
+        self.hits[(addr % MAX_ROM) as usize]
+    }
+
+    pub fn max_hits(&self) -> u64 {
+        self.hits.iter().copied().max().unwrap_or(0)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.hits.iter().sum()
+    }
+
+    /// Executions per instruction form, keyed by its index in the machine's ISA.
+    pub fn forms(&self) -> &BTreeMap<usize, u64> {
+        &self.forms
+    }
+
+    /// Taken and not-taken counts of the `brc` or `ibr` at `addr`.
+    pub fn branch(&self, addr: u16) -> (u64, u64) {
+        (self.taken[(addr % MAX_ROM) as usize], self.not_taken[(addr % MAX_ROM) as usize])
+    }
+
+    /// Loops ordered by the instructions executed in their body, hottest first.
+    pub fn loops(&self) -> Vec<Loop> {
+        let mut loops: Vec<Loop> = self.back_edges.iter()
+            .map(|(&(end, start), &iterations)| {
+                let body = start as usize..=end as usize;
+                Loop {
+                    start,
+                    end,
+                    iterations,
+                    instructions: self.hits[body.clone()].iter().sum(),
+                    ticks: self.ticks[body].iter().sum(),
+                }
+            })
+            .collect();
+        loops.sort_by(|a, b| b.instructions.cmp(&a.instructions).then(a.start.cmp(&b.start)));
+        loops
+    }
+
+    /// Human-readable summary of the hottest loops, addresses, opcodes and branches.
+    pub fn report(&self, cpu: &Cpu, limit: usize) -> String {
+        let total = self.total();
+        let total_ticks: u64 = self.ticks.iter().sum();
+        let share = |count: u64| match total {
+            0 => 0.0,
+            _ => count as f64 * 100.0 / total as f64,
+        };
+        let text = |addr: u16| cpu.machine().format(cpu.read_from_rom(addr));
+
+        let mut report = format!("profile: {} instructions, {} ticks\n", total, total_ticks);
+
+        report.push_str("hottest loops:\n");
+        for l in self.loops().iter().take(limit) {
+            report.push_str(&format!("  {:02}..{:02}  {:>8} iterations  {:>10} instructions ({:5.1}%)  {:>10} ticks\n",
+                                     l.start, l.end, l.iterations, l.instructions, share(l.instructions), l.ticks));
+        }
+
+        report.push_str("hottest addresses:\n");
+        let mut addresses: Vec<u16> = (0..MAX_ROM).filter(|&addr| self.hits[addr as usize] > 0).collect();
+        addresses.sort_by(|&a, &b| self.hits[b as usize].cmp(&self.hits[a as usize]).then(a.cmp(&b)));
+        for &addr in addresses.iter().take(limit) {
+            report.push_str(&format!("  {:02}  {:<20} {:>10} ({:5.1}%)\n", addr, text(addr), self.hits[addr as usize], share(self.hits[addr as usize])));
+        }
+
+        // Forms of one mnemonic share a line, in the order the ISA lists them.
+        let mut mnemonics: Vec<(&str, u64)> = Vec::new();
+        for (&form, &count) in &self.forms {
+            let mnemonic = cpu.machine().isa.definitions()[form].mnemonic.as_str();
+            match mnemonics.iter_mut().find(|(m, _)| *m == mnemonic) {
+                Some((_, total)) => *total += count,
+                None => mnemonics.push((mnemonic, count)),
+            }
+        }
+        report.push_str("opcodes:\n");
+        for (mnemonic, count) in mnemonics {
+            report.push_str(&format!("  {}  {:>10} ({:5.1}%)\n", mnemonic, count, share(count)));
+        }
+
+        report.push_str("branches:\n");
+        for addr in (0..MAX_ROM).filter(|&addr| self.taken[addr as usize] + self.not_taken[addr as usize] > 0) {
+            let (taken, not_taken) = self.branch(addr);
+            report.push_str(&format!("  {:02}  {:<20} taken {:>8}  not taken {:>8}  ({:5.1}% taken)\n",
+                                     addr, text(addr), taken, not_taken, taken as f64 * 100.0 / (taken + not_taken) as f64));
+        }
+
+        report
+    }
+}
@@ -0,0 +1,313 @@
+//! Save states: the full machine state in a small versioned text format.
+//!
+//! ```text
+//! anpu-nano-state 2
+//! machine rom=64 ram=32 registers=8
+//! rom 8100 8701 ... (one word per ROM address)
+//! ram 05 03 ... (one byte per RAM cell)
+//! reg 00 07 ...
+//! inp 00 00 ...
+//! out 00 00 ...
+//! flg 0000000000000001   (flag 0 first)
+//! pc 12
+//! stk 05 0c ...   (machines with a call stack: one byte per slot)
+//! sp 2
+//! executed 502
+//! ticks 5020
+//! ```
+//!
+//! Values are hex except `machine`, `flg`, `pc`, `sp`, `executed` and `ticks`. Unknown
+//! keys are rejected so a newer file never loads half-understood. A state only
+//! loads into a CPU with the same machine sizes, which the `machine` line gives
+//! before any other key; version 1 files, which have no `machine` line, are for
+//! the stock AnPU Nano. The `machine` line lists
+//! `stack=N` and the file has `stk` and `sp` only for machines with a call stack.
+
+use std::fmt;
+
+use crate::{cpu::Cpu, machine::{check_size, check_stack, Machine}};
+
+const MAGIC: &str = "anpu-nano-state";
+pub const VERSION: u32 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line + 1, self.message)
+    }
+}
+
+fn error(line: usize, message: String) -> StateError {
+    StateError { line, message }
+}
+
+fn hex_row(values: impl Iterator<Item = u32>, width: usize) -> String {
+    values.map(|v| format!("{v:0>0$x}", width)).collect::<Vec<_>>().join(" ")
+}
+
+/// Parses exactly `count` hex values no larger than `max`.
+fn hex_values(line: usize, text: &str, count: usize, max: u32) -> Result<Vec<u32>, StateError> {
+    let values: Vec<u32> = text.split_whitespace()
+        .map(|v| u32::from_str_radix(v, 16).ok().filter(|&v| v <= max))
+        .collect::<Option<_>>()
+        .ok_or_else(|| error(line, format!("invalid value in '{}'", text)))?;
+    match values.len() == count {
+        true => Ok(values),
+        false => Err(error(line, format!("expected {} values, found {}", count, values.len()))),
+    }
+}
+
+/// Parses `rom=N ram=N registers=N stack=N`; sizes that are left out keep the stock value.
+fn machine_sizes(line: usize, text: &str) -> Result<Machine, StateError> {
+    let mut machine = Machine::default();
+    for entry in text.split_whitespace() {
+        let size = entry.split_once('=').and_then(|(key, value)| Some((key, value.parse::<u16>().ok()?)));
+        let checked = match size {
+            Some(("rom", size)) => check_size("rom", size).map(|_| machine.rom = size),
+            Some(("ram", size)) => check_size("ram", size).map(|_| machine.ram = size),
+            Some(("registers", size)) => check_size("registers", size).map(|_| machine.registers = size),
+            Some(("stack", size)) => check_stack(size).map(|_| machine.stack = size),
+            _ => return Err(error(line, format!("invalid machine size '{}'", entry))),
+        };
+        checked.map_err(|message| error(line, message))?;
+    }
+    Ok(machine)
+}
+
+impl Cpu {
+    pub fn save_state(&self) -> String {
+        let flags: String = self.flg.iter().map(|&flag| if flag { '1' } else { '0' }).collect();
+        let row = |values: &[u8]| hex_row(values.iter().map(|&v| v as u32), 2);
+
+        let machine = self.machine();
+        let mut text = format!("{} {}\n", MAGIC, VERSION);
+        text.push_str(&format!("machine rom={} ram={} registers={}", machine.rom, machine.ram, machine.registers));
+        if machine.stack > 0 {
+            text.push_str(&format!(" stack={}", machine.stack));
+        }
+        text.push('\n');
+        text.push_str(&format!("rom {}\n", hex_row(self.rom.iter().map(|&w| w as u32), 4)));
+        text.push_str(&format!("ram {}\n", row(&self.ram)));
+        text.push_str(&format!("reg {}\n", row(&self.reg)));
+        text.push_str(&format!("inp {}\n", row(&self.inp)));
+        text.push_str(&format!("out {}\n", row(&self.out)));
+        text.push_str(&format!("flg {}\n", flags));
+        text.push_str(&format!("pc {}\n", self.pc % machine.rom));
+        if machine.stack > 0 {
+            text.push_str(&format!("stk {}\n", row(&self.stack)));
+            text.push_str(&format!("sp {}\n", self.sp));
+        }
+        text.push_str(&format!("executed {}\n", self.executed_instructions));
+        text.push_str(&format!("ticks {}\n", self.elapsed_ticks));
+
+        text
+    }
+
+    /// Restores a state written by `save_state`. Nothing is changed unless the
+    /// whole file parses.
+    pub fn load_state(&mut self, text: &str) -> Result<(), StateError> {
+        let mut lines = text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+
+        let version = match lines.next().map(|(_, line)| line.split_whitespace().collect::<Vec<_>>()) {
+            Some(header) if header.len() == 2 && header[0] == MAGIC => match header[1].parse::<u32>() {
+                Ok(version @ (1 | VERSION)) => version,
+                _ => return Err(error(0, format!("unsupported save state version '{}'", header[1]))),
+            },
+            _ => return Err(error(0, "not an AnPU Nano save state".to_string())),
+        };
+
+        // The sizes decide how many values the other lines hold, so they come first.
+        let mut machine = match version {
+            1 => Some(Machine::default()),
+            _ => None,
+        };
+        let mut rom = None;
+        let mut ram = None;
+        let mut reg = None;
+        let mut inp = None;
+        let mut out = None;
+        let mut flg = None;
+        let mut pc = None;
+        let mut stk = None;
+        let mut sp = None;
+        let mut executed = None;
+        let mut ticks = None;
+
+        for (line, raw) in lines {
+            let (key, value) = raw.trim().split_once(' ').unwrap_or((raw.trim(), ""));
+            let value = value.trim();
+            let number = || value.parse::<u64>().map_err(|_| error(line, format!("invalid number '{}'", value)));
+            let in_range = |name: &str, max: u16| match number()? {
+                value if value <= max as u64 => Ok(value as u16),
+                value => Err(error(line, format!("{} {} out of range (0-{})", name, value, max))),
+            };
+            if key == "machine" {
+                match machine {
+                    None => machine = Some(machine_sizes(line, value)?),
+                    Some(_) => return Err(error(line, "unexpected 'machine' line".to_string())),
+                }
+                continue;
+            }
+            let machine = machine.as_ref().ok_or_else(|| error(line, format!("'{}' before the 'machine' line", key)))?;
+            match key {
+                "rom" => rom = Some(hex_values(line, value, machine.rom as usize, 0xffff)?),
+                "ram" => ram = Some(hex_values(line, value, machine.ram as usize, 0xff)?),
+                "reg" => reg = Some(hex_values(line, value, machine.registers as usize, 0xff)?),
+                "inp" => inp = Some(hex_values(line, value, 8, 0xff)?),
+                "out" => out = Some(hex_values(line, value, 8, 0xff)?),
+                "flg" => match value.len() == 16 && value.chars().all(|c| c == '0' || c == '1') {
+                    true => flg = Some(std::array::from_fn::<bool, 16, _>(|idx| &value[idx..idx + 1] == "1")),
+                    false => return Err(error(line, format!("invalid flags '{}'", value))),
+                },
+                "pc" => pc = Some(in_range("pc", machine.rom - 1)?),
+                "stk" => stk = Some(hex_values(line, value, machine.stack as usize, 0xff)?),
+                "sp" => sp = Some(in_range("sp", machine.stack)?),
+                "executed" => executed = Some(number()? as usize),
+                "ticks" => ticks = Some(number()?),
+                _ => return Err(error(line, format!("unknown key '{}'", key))),
+            }
+        }
+
+        let machine = machine.ok_or_else(|| error(0, "missing 'machine'".to_string()))?;
+        if !machine.same_geometry(self.machine()) {
+            let stack = match machine.stack {
+                0 => "no call stack".to_string(),
+                depth => format!("a {}-deep call stack", depth),
+            };
+            return Err(error(0, format!("state is for a machine with {} words ROM, {} bytes RAM, {} registers and {}",
+                                        machine.rom, machine.ram, machine.registers, stack)));
+        }
+
+        let missing = |name: &str| error(0, format!("missing '{}'", name));
+        let rom = rom.ok_or_else(|| missing("rom"))?;
+        let ram = ram.ok_or_else(|| missing("ram"))?;
+        let reg = reg.ok_or_else(|| missing("reg"))?;
+        let inp = inp.ok_or_else(|| missing("inp"))?;
+        let out = out.ok_or_else(|| missing("out"))?;
+        let flg = flg.ok_or_else(|| missing("flg"))?;
+        let pc = pc.ok_or_else(|| missing("pc"))?;
+        let (stk, sp) = match machine.stack {
+            0 => (Vec::new(), 0),
+            _ => (stk.ok_or_else(|| missing("stk"))?, sp.ok_or_else(|| missing("sp"))?),
+        };
+
+        for (idx, word) in rom.into_iter().enumerate() {
+            self.write_to_rom(idx as u16, word as u16);
+        }
+        self.ram = ram.into_iter().map(|v| v as u8).collect();
+        self.reg = reg.into_iter().map(|v| v as u8).collect();
+        for (port, (i, o)) in inp.into_iter().zip(out).enumerate() {
+            self.inp[port] = i as u8;
+            self.out[port] = o as u8;
+        }
+        self.flg = flg;
+        self.pc = pc;
+        self.stack = stk.into_iter().map(|v| v as u8).collect();
+        self.sp = sp;
+        self.executed_instructions = executed.unwrap_or(0);
+        self.elapsed_ticks = ticks.unwrap_or(0);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::isa::Isa;
+
+    /// A CPU with every part of its state set to something other than the reset value.
+    fn busy_cpu(machine: Machine) -> Cpu {
+        let mut cpu = Cpu::with_machine(machine);
+        for idx in 0..cpu.rom.len() {
+            cpu.write_to_rom(idx as u16, (idx as u16).wrapping_mul(0x1357));
+        }
+        for (idx, cell) in cpu.ram.iter_mut().enumerate() {
+            *cell = idx as u8 ^ 0xa5;
+        }
+        for (idx, reg) in cpu.reg.iter_mut().enumerate() {
+            *reg = idx as u8 * 17;
+        }
+        cpu.inp = [1, 2, 3, 4, 5, 6, 7, 8];
+        cpu.out = [8, 7, 6, 5, 4, 3, 2, 1];
+        cpu.flg = std::array::from_fn(|idx| idx % 3 == 0);
+        cpu.pc = 12;
+        for (idx, slot) in cpu.stack.iter_mut().enumerate() {
+            *slot = idx as u8 + 40;
+        }
+        cpu.sp = cpu.machine().stack / 2;
+        cpu.executed_instructions = 502;
+        cpu.elapsed_ticks = 5020;
+        cpu
+    }
+
+    fn assert_same_state(loaded: &Cpu, saved: &Cpu) {
+        assert_eq!(loaded.rom, saved.rom);
+        assert_eq!(loaded.ram, saved.ram);
+        assert_eq!(loaded.reg, saved.reg);
+        assert_eq!((loaded.inp, loaded.out, loaded.flg, loaded.pc), (saved.inp, saved.out, saved.flg, saved.pc));
+        assert_eq!((&loaded.stack, loaded.sp), (&saved.stack, saved.sp));
+        assert_eq!((loaded.executed_instructions, loaded.elapsed_ticks), (saved.executed_instructions, saved.elapsed_ticks));
+    }
+
+    #[test]
+    fn round_trips() {
+        let stacked = Machine { stack: 8, isa: Isa::nano_with(true, false), ..Machine::preset("wide16").unwrap() };
+        for machine in [Machine::default(), Machine::preset("wide").unwrap(), stacked] {
+            let saved = busy_cpu(machine.clone());
+            let text = saved.save_state();
+
+            let mut loaded = Cpu::with_machine(machine);
+            loaded.load_state(&text).unwrap();
+            assert_same_state(&loaded, &saved);
+            assert_eq!(loaded.save_state(), text);
+        }
+    }
+
+    #[test]
+    fn loads_version_1() {
+        let saved = busy_cpu(Machine::default());
+        let text = saved.save_state()
+            .replacen("anpu-nano-state 2", "anpu-nano-state 1", 1)
+            .replacen("machine rom=64 ram=32 registers=8\n", "", 1);
+        assert!(text.starts_with("anpu-nano-state 1\nrom "));
+
+        let mut loaded = Cpu::new();
+        loaded.load_state(&text).unwrap();
+        assert_same_state(&loaded, &saved);
+    }
+
+    #[test]
+    fn rejects_bad_states() {
+        let text = busy_cpu(Machine::default()).save_state();
+        let cases = [
+            (text.replacen("anpu-nano-state 2", "anpu-nano-state 3", 1), 0, "unsupported save state version '3'"),
+            (text.replacen("rom=64", "rom=0", 1), 1, "rom must be a power of two from 2 to 256, not 0"),
+            (text.replacen("ram=32", "ram=128", 1), 3, "expected 128 values, found 32"),
+            (text.replacen("registers=8", "registers=8 stack=4", 1), 0, "state is for a machine with 64 words ROM, 32 bytes RAM, 8 registers and a 4-deep call stack"),
+            (text.replacen("pc 12\n", "", 1), 0, "missing 'pc'"),
+            (text.replacen("pc 12", "pc 64", 1), 8, "pc 64 out of range (0-63)"),
+            (text.replacen("machine rom=64 ram=32 registers=8\n", "", 1), 1, "'rom' before the 'machine' line"),
+            (text.replacen("machine rom=64 ram=32 registers=8\n", "", 1).replacen("ticks", "machine rom=64\nticks", 1), 1,
+             "'rom' before the 'machine' line"),
+            (text.replacen("pc 12\n", "pc 12\nmachine rom=64\n", 1), 9, "unexpected 'machine' line"),
+            (text.replacen("anpu-nano-state 2", "anpu-nano-state 1", 1), 1, "unexpected 'machine' line"),
+            (text.replacen("ticks", "speed", 1), 10, "unknown key 'speed'"),
+        ];
+        for (text, line, message) in cases {
+            let mut cpu = Cpu::new();
+            assert_eq!(cpu.load_state(&text), Err(StateError { line, message: message.to_string() }), "{}", message);
+            assert_eq!(cpu.pc, 0, "{}", message);
+        }
+
+        let stacked = Machine { stack: 4, isa: Isa::nano_with(true, false), ..Machine::default() };
+        let text = busy_cpu(stacked.clone()).save_state().replacen("sp 2", "sp 5", 1);
+        let mut cpu = Cpu::with_machine(stacked);
+        assert_eq!(cpu.load_state(&text), Err(StateError { line: 10, message: "sp 5 out of range (0-4)".to_string() }));
+    }
+}
@@ -0,0 +1,122 @@
+//! Game-tick cost model for predicting how long a program runs on the in-game
+//! redstone build, as opposed to how long the emulator takes on the host.
+
+use std::{fmt, str::FromStr};
+
+use crate::{asm::parse_number, instruction::Instruction};
+
+/// Redstone ticks per second of game time.
+pub const TICKS_PER_SECOND: u32 = 10;
+
+/// Instruction groups that share a datapath, and therefore a cost, on the hardware.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Class {
+    /// `add`, `sub`, `and`, `nor`, `xor`, `rsh`, `cmp` and `cms`.
+    Alu,
+    /// `imm`.
+    Immediate,
+    /// `dml`, `dms`, `iml` and `ims`.
+    Memory,
+    /// `brc`, `ibr` and `jmp`, taken or not, and `cal` and `ret`.
+    Branch,
+    /// `int`.
+    Interrupt,
+}
+
+impl Instruction {
+    pub fn class(&self) -> Class {
+        match self {
+            Instruction::Add { .. }
+            | Instruction::Sub { .. }
+            | Instruction::And { .. }
+            | Instruction::Nor { .. }
+            | Instruction::Xor { .. }
+            | Instruction::Rsh { .. }
+            | Instruction::Cmp { .. } => Class::Alu,
+            Instruction::Imm { .. } => Class::Immediate,
+            Instruction::Dml { .. }
+            | Instruction::Dms { .. }
+            | Instruction::Iml { .. }
+            | Instruction::Ims { .. } => Class::Memory,
+            Instruction::Brc { .. }
+            | Instruction::Ibr { .. }
+            | Instruction::Jmp { .. }
+            | Instruction::Cal { .. }
+            | Instruction::Ret => Class::Branch,
+            Instruction::Int { .. } => Class::Interrupt,
+        }
+    }
+}
+
+/// Redstone ticks each instruction class takes. The defaults describe the
+/// stock build, which clocks every instruction in a fixed 10-tick cycle;
+/// builds with a faster ALU or slower memory should override them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TickCosts {
+    pub alu: u32,
+    pub imm: u32,
+    pub memory: u32,
+    pub branch: u32,
+    pub int: u32,
+}
+
+impl Default for TickCosts {
+    fn default() -> Self {
+        TickCosts { alu: 10, imm: 10, memory: 10, branch: 10, int: 10 }
+    }
+}
+
+impl TickCosts {
+    pub fn cost(&self, instruction: &Instruction) -> u32 {
+        match instruction.class() {
+            Class::Alu => self.alu,
+            Class::Immediate => self.imm,
+            Class::Memory => self.memory,
+            Class::Branch => self.branch,
+            Class::Interrupt => self.int,
+        }
+    }
+}
+
+impl fmt::Display for TickCosts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "alu={},imm={},mem={},branch={},int={}", self.alu, self.imm, self.memory, self.branch, self.int)
+    }
+}
+
+/// Parses comma-separated overrides such as `alu=8,mem=14`; classes that are
+/// not mentioned keep their default cost.
+impl FromStr for TickCosts {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut costs = TickCosts::default();
+        for entry in text.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (class, ticks) = entry.split_once('=').ok_or_else(|| format!("invalid tick cost '{}'", entry))?;
+            let ticks = parse_number(ticks.trim()).ok_or_else(|| format!("invalid tick count '{}'", ticks.trim()))?;
+            match class.trim().to_ascii_lowercase().as_str() {
+                "alu" => costs.alu = ticks,
+                "imm" => costs.imm = ticks,
+                "mem" | "memory" => costs.memory = ticks,
+                "branch" => costs.branch = ticks,
+                "int" => costs.int = ticks,
+                _ => return Err(format!("unknown instruction class '{}'", class.trim())),
+            }
+        }
+
+        Ok(costs)
+    }
+}
+
+/// Game time taken by `ticks` redstone ticks, in seconds.
+pub fn seconds(ticks: u64) -> f64 {
+    ticks as f64 / TICKS_PER_SECOND as f64
+}
+
+/// Average in-game clock rate over a run, in instructions per second.
+pub fn frequency(instructions: usize, ticks: u64) -> f64 {
+    match ticks {
+        0 => 0.0,
+        _ => instructions as f64 / seconds(ticks),
+    }
+}
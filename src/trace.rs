@@ -0,0 +1,347 @@
+//! Execution traces: one record per executed instruction, for diffing runs and
+//! checking the emulator against recordings from the physical machine.
+//!
+//! The text format is one line per record:
+//!
+//! ```text
+//! cycle    pc word disassembly          write          flags (0 first)
+//!      12  05 1427 add 4, 2, 7          r4 00->05      0101010110010101
+//! ```
+//!
+//! Only the text format carries a disassembly, so it is decoded for the
+//! machine the trace is written for; binary records hold the raw word.
+//!
+//! The binary format starts with `ANPT` and a version byte, followed by
+//! fixed 17-byte little-endian records: cycle (u64), pc (u8), word (u16),
+//! flags (u16), write kind (u8: 0 none, 1 reg, 2 ram, 3 out, 4 stack), index, old and new (u8 each).
+//! Version 2 added the stack kind; version 1 files still load.
+//!
+//! Stepping backwards in the TUI does not rewrite the trace; the re-executed
+//! instructions are recorded again with their repeated cycle numbers.
+
+use std::{fmt, io, ops::RangeInclusive};
+
+use crate::{cpu::{Cpu, Step, Write}, debug::Location, history::{pack_flags, unpack_flags}, machine::Machine};
+
+const MAGIC: &[u8; 4] = b"ANPT";
+const VERSION: u8 = 2;
+const RECORD_SIZE: usize = 17;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Binary,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// `executed_instructions` after this instruction.
+    pub cycle: u64,
+    pub pc: u16,
+    pub word: u16,
+    pub write: Option<Write>,
+    /// Flags after execution, flag 0 in the lowest bit.
+    pub flags: u16,
+}
+
+impl Record {
+    pub fn new(cpu: &Cpu, step: &Step) -> Self {
+        Record {
+            cycle: cpu.executed_instructions as u64,
+            pc: step.pc,
+            word: step.word,
+            write: step.write,
+            flags: pack_flags(&cpu.flg),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; RECORD_SIZE] {
+        let (kind, idx, old, new) = match self.write {
+            None => (0, 0, 0, 0),
+            Some(Write::Reg { idx, old, new }) => (1, idx, old, new),
+            Some(Write::Ram { idx, old, new }) => (2, idx, old, new),
+            Some(Write::Out { idx, old, new }) => (3, idx, old, new),
+            Some(Write::Stack { idx, old, new }) => (4, idx, old, new),
+        };
+        let mut bytes = [0; RECORD_SIZE];
+        bytes[0..8].copy_from_slice(&self.cycle.to_le_bytes());
+        bytes[8] = self.pc as u8;
+        bytes[9..11].copy_from_slice(&self.word.to_le_bytes());
+        bytes[11..13].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[13..17].copy_from_slice(&[kind, idx as u8, old, new]);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8], version: u8) -> Option<Self> {
+        let (idx, old, new) = (bytes[14] as u16, bytes[15], bytes[16]);
+        let write = match bytes[13] {
+            0 => None,
+            1 => Some(Write::Reg { idx, old, new }),
+            2 => Some(Write::Ram { idx, old, new }),
+            3 => Some(Write::Out { idx, old, new }),
+            4 if version >= 2 => Some(Write::Stack { idx, old, new }),
+            _ => return None,
+        };
+        Some(Record {
+            cycle: u64::from_le_bytes(bytes[0..8].try_into().ok()?),
+            pc: bytes[8] as u16,
+            word: u16::from_le_bytes([bytes[9], bytes[10]]),
+            write,
+            flags: u16::from_le_bytes([bytes[11], bytes[12]]),
+        })
+    }
+
+    fn parse_line(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 5 {
+            return None;
+        }
+        let flags = fields[fields.len() - 1];
+        let write = match fields[fields.len() - 2] {
+            "-" => None,
+            values => {
+                let location: Location = fields[fields.len() - 3].parse().ok()?;
+                let (old, new) = values.split_once("->")?;
+                let (old, new) = (u8::from_str_radix(old, 16).ok()?, u8::from_str_radix(new, 16).ok()?);
+                Some(match location {
+                    Location::Reg(idx) => Write::Reg { idx, old, new },
+                    Location::Ram(idx) => Write::Ram { idx, old, new },
+                    Location::Out(idx) => Write::Out { idx, old, new },
+                    Location::Stack(idx) => Write::Stack { idx, old, new },
+                })
+            }
+        };
+        if flags.len() != 16 || !flags.chars().all(|c| c == '0' || c == '1') {
+            return None;
+        }
+        Some(Record {
+            cycle: fields[0].parse().ok()?,
+            pc: fields[1].parse().ok()?,
+            word: u16::from_str_radix(fields[2], 16).ok()?,
+            write,
+            flags: unpack_flags_text(flags),
+        })
+    }
+}
+
+fn unpack_flags_text(text: &str) -> u16 {
+    text.chars().enumerate().fold(0, |acc, (idx, c)| acc | ((c == '1') as u16) << idx)
+}
+
+impl Record {
+    /// The record as a line of the text format, disassembled for `machine`.
+    pub fn text(&self, machine: &Machine) -> String {
+        let write = match self.write {
+            Some(Write::Reg { idx, old, new }) => format!("{} {:02x}->{:02x}", Location::Reg(idx), old, new),
+            Some(Write::Ram { idx, old, new }) => format!("{} {:02x}->{:02x}", Location::Ram(idx), old, new),
+            Some(Write::Out { idx, old, new }) => format!("{} {:02x}->{:02x}", Location::Out(idx), old, new),
+            Some(Write::Stack { idx, old, new }) => format!("{} {:02x}->{:02x}", Location::Stack(idx), old, new),
+            None => "-".to_string(),
+        };
+        let flags: String = unpack_flags(self.flags).iter().map(|&flag| if flag { '1' } else { '0' }).collect();
+        format!("{:>8}  {:02} {:04x} {:<20} {:<14} {}", self.cycle, self.pc, self.word, machine.format(self.word), write, flags)
+    }
+}
+
+/// Streams records to `writer` as they are produced.
+pub struct TraceWriter<W: io::Write> {
+    writer: W,
+    format: Format,
+    machine: Machine,
+}
+
+impl<W: io::Write> TraceWriter<W> {
+    pub fn new(mut writer: W, format: Format, machine: &Machine) -> io::Result<Self> {
+        if format == Format::Binary {
+            writer.write_all(MAGIC)?;
+            writer.write_all(&[VERSION])?;
+        }
+        Ok(TraceWriter { writer, format, machine: machine.clone() })
+    }
+
+    /// Records `step` if it executed; `cpu` is the state after it. A waiting
+    /// `input` or a stack fault changed nothing and writes no record.
+    pub fn step(&mut self, cpu: &Cpu, step: &Step) -> io::Result<()> {
+        match step.executed {
+            true => self.record(Record::new(cpu, step)),
+            false => Ok(()),
+        }
+    }
+
+    pub fn record(&mut self, record: Record) -> io::Result<()> {
+        match self.format {
+            Format::Text => writeln!(self.writer, "{}", record.text(&self.machine)),
+            Format::Binary => self.writer.write_all(&record.to_bytes()),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceError {
+    /// Line (text) or record index (binary) that failed to parse.
+    pub record: usize,
+    pub message: String,
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "record {}: {}", self.record + 1, self.message)
+    }
+}
+
+/// Reads a trace in either format, telling them apart by the binary header.
+pub fn read(bytes: &[u8]) -> Result<Vec<Record>, TraceError> {
+    let error = |record, message: &str| TraceError { record, message: message.to_string() };
+
+    if let Some(body) = bytes.strip_prefix(MAGIC) {
+        let version = match body.first() {
+            Some(&version @ (1 | VERSION)) => version,
+            _ => return Err(error(0, "unsupported trace version")),
+        };
+        let body = &body[1..];
+        if !body.len().is_multiple_of(RECORD_SIZE) {
+            return Err(error(body.len() / RECORD_SIZE, "truncated record"));
+        }
+        return body.chunks(RECORD_SIZE).enumerate()
+            .map(|(idx, chunk)| Record::from_bytes(chunk, version).ok_or_else(|| error(idx, "invalid write kind")))
+            .collect();
+    }
+
+    let text = std::str::from_utf8(bytes).map_err(|_| error(0, "not a trace file"))?;
+    text.lines().enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(idx, line)| Record::parse_line(line).ok_or_else(|| error(idx, "malformed line")))
+        .collect()
+}
+
+/// Selects records by ROM address and mnemonic; empty criteria match everything.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    pub pc: Option<RangeInclusive<u16>>,
+    pub mnemonics: Vec<String>,
+}
+
+impl Filter {
+    /// Whether `record`, disassembled for `machine`, passes the filter.
+    pub fn matches(&self, record: &Record, machine: &Machine) -> bool {
+        let pc = self.pc.as_ref().is_none_or(|range| range.contains(&record.pc));
+        let mnemonic = self.mnemonics.is_empty()
+            || self.mnemonics.iter().any(|m| m.eq_ignore_ascii_case(&machine.isa.definition(record.word).mnemonic));
+        pc && mnemonic
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, batch, isa::Isa};
+
+    /// Records of a run that writes every kind of location.
+    fn run() -> (Machine, Vec<Record>) {
+        let machine = Machine { stack: 2, isa: Isa::nano_with(true, false), ..Machine::default() };
+        let source = "\
+        imm r1, 0x2a
+        cal store
+        cmp r1, r0
+        int
+store:  dms r1, 3
+        dms r1, out2
+        ret
+";
+        let mut cpu = Cpu::with_machine(machine.clone());
+        for (idx, word) in assemble(source, &machine).unwrap().into_iter().enumerate() {
+            cpu.write_to_rom(idx as u16, word);
+        }
+        let mut records = Vec::new();
+        batch::run_with(&mut cpu, 100, |cpu, step| {
+            records.push(Record::new(cpu, step));
+            Ok::<_, ()>(())
+        }).unwrap();
+        (machine, records)
+    }
+
+    fn write(records: &[Record], format: Format, machine: &Machine) -> Vec<u8> {
+        let mut writer = TraceWriter::new(Vec::new(), format, machine).unwrap();
+        for &record in records {
+            writer.record(record).unwrap();
+        }
+        writer.writer
+    }
+
+    #[test]
+    fn round_trips_between_formats() {
+        let (machine, records) = run();
+        let kinds: Vec<u8> = records.iter().map(|record| record.to_bytes()[13]).collect();
+        assert_eq!(kinds, [1, 4, 2, 3, 0, 0, 0]);
+
+        for format in [Format::Text, Format::Binary] {
+            assert_eq!(read(&write(&records, format, &machine)), Ok(records.clone()), "{:?}", format);
+        }
+        let binary = read(&write(&records, Format::Binary, &machine)).unwrap();
+        let text = read(&write(&binary, Format::Text, &machine)).unwrap();
+        assert_eq!(write(&text, Format::Binary, &machine), write(&records, Format::Binary, &machine));
+    }
+
+    #[test]
+    fn reads_version_1() {
+        let (machine, records) = run();
+        let mut binary = write(&records, Format::Binary, &machine);
+        binary[4] = 1;
+        // Version 1 predates stack writes.
+        assert_eq!(read(&binary), Err(TraceError { record: 1, message: "invalid write kind".to_string() }));
+
+        let without_stack: Vec<Record> = records.into_iter().filter(|record| !matches!(record.write, Some(Write::Stack { .. }))).collect();
+        let mut binary = write(&without_stack, Format::Binary, &machine);
+        binary[4] = 1;
+        assert_eq!(read(&binary), Ok(without_stack));
+    }
+
+    #[test]
+    fn skips_steps_that_did_not_execute() {
+        let cases = [(Machine::default(), "imm r1, 5\nint 3, 2", batch::Status::WaitingForInput),
+                     (Machine { stack: 1, isa: Isa::nano_with(true, false), ..Machine::default() }, "imm r1, 5\nret",
+                      batch::Status::Fault(crate::cpu::StackFault::Underflow))];
+        for (machine, source, expected) in cases {
+            let mut cpu = Cpu::with_machine(machine.clone());
+            for (idx, word) in assemble(source, &machine).unwrap().into_iter().enumerate() {
+                cpu.write_to_rom(idx as u16, word);
+            }
+            let mut writer = TraceWriter::new(Vec::new(), Format::Binary, &machine).unwrap();
+            let status = batch::run_with(&mut cpu, 10, |cpu, step| writer.step(cpu, step)).unwrap();
+            assert_eq!(status, expected);
+
+            let records = read(&writer.writer).unwrap();
+            assert_eq!(records.iter().map(|record| (record.cycle, record.pc)).collect::<Vec<_>>(), [(1, 0)], "{}", source);
+        }
+    }
+
+    #[test]
+    fn rejects_damaged_traces() {
+        let (machine, records) = run();
+        let binary = write(&records, Format::Binary, &machine);
+        let mut bad_kind = binary.clone();
+        bad_kind[5 + RECORD_SIZE + 13] = 9;
+        let mut bad_version = binary.clone();
+        bad_version[4] = 0;
+        let text = write(&records, Format::Text, &machine);
+        let mut bad_line = text.clone();
+        bad_line.splice(0..0, b"12 not a record\n".iter().copied());
+
+        let cases: [(&[u8], usize, &str); 7] = [
+            (&binary[..binary.len() - 1], records.len() - 1, "truncated record"),
+            (&binary[..5 + 3], 0, "truncated record"),
+            (&binary[..4], 0, "unsupported trace version"),
+            (&bad_version, 0, "unsupported trace version"),
+            (&bad_kind, 1, "invalid write kind"),
+            (&bad_line, 0, "malformed line"),
+            (&[0xff, 0xfe, 0x00], 0, "not a trace file"),
+        ];
+        for (bytes, record, message) in cases {
+            assert_eq!(read(bytes), Err(TraceError { record, message: message.to_string() }), "{}", message);
+        }
+    }
+}